@@ -1,14 +1,242 @@
+use iris::forwarder::{self, Forwarder, UpstreamStrategy};
 use iris::resolver::Resolver;
+use iris::root_hints::RootHints;
+use iris::thread_pool::ThreadPool;
+use iris::zone::Zone;
+
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const UDP_WORKER_COUNT: usize = 16;
+const UDP_QUEUE_SIZE: usize = 256;
+// Each connection gets its own thread, as transfers and slow clients may take a while
+const MAX_TCP_CONNECTIONS: usize = 64;
+const ZONE_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// A served zone's file, which is reloaded once it's been modified
+struct ZoneFile {
+    origin: String,
+    path: String,
+    modified_at: Option<SystemTime>,
+}
+
+fn get_modified_at(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn watch_zone_files(resolver: &Resolver, mut zone_files: Vec<ZoneFile>) {
+    loop {
+        thread::sleep(ZONE_FILE_CHECK_INTERVAL);
+
+        for zone_file in zone_files.iter_mut() {
+            let modified_at = get_modified_at(&zone_file.path);
+            if modified_at == zone_file.modified_at {
+                continue;
+            }
+            zone_file.modified_at = modified_at;
+
+            // Secondaries are notified by add_zone if the serial went up
+            match Zone::from_file(&zone_file.origin, &zone_file.path) {
+                Ok(zone) => {
+                    println!(
+                        "Reloaded zone {} with serial {}",
+                        zone_file.origin,
+                        zone.get_serial()
+                    );
+                    resolver.add_zone(zone);
+                }
+                Err(e) => eprintln!(
+                    "Failed to reload zone {}, keeping the previous version: {}",
+                    zone_file.origin, e
+                ),
+            }
+        }
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let port = 2053;
-    let resolver = Resolver::new("127.0.0.1", port)?;
+    let mut listen_addr = "127.0.0.1".to_string();
+    let mut root_hints = None;
+    let mut upstreams = Vec::new();
+    let mut upstream_strategy = UpstreamStrategy::Ordered;
+    let mut zone_upstreams = Vec::new();
+    let mut zones = Vec::new();
+    let mut zone_files = Vec::new();
+    let mut transfer_allow_list = Vec::new();
+    let mut secondary_zones = Vec::new();
+    let mut notify_list = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => {
+                listen_addr = args.next().ok_or("Missing address after --listen")?;
+            }
+            "--root-hints" => {
+                let path = args.next().ok_or("Missing path after --root-hints")?;
+                root_hints = Some(RootHints::from_file(&path)?);
+            }
+            "--forward" => {
+                let upstream = args.next().ok_or("Missing address after --forward")?;
+                upstreams.push(forwarder::parse_upstream(&upstream)?);
+            }
+            "--forward-strategy" => {
+                let strategy = args.next().ok_or("Missing name after --forward-strategy")?;
+                upstream_strategy = strategy.parse()?;
+            }
+            "--forward-zone" => {
+                let zone = args.next().ok_or("Missing zone after --forward-zone")?;
+                let (domain, addrs) = zone
+                    .split_once('=')
+                    .ok_or("Expected --forward-zone <domain>=<address>[,<address>...]")?;
+                let addrs = addrs
+                    .split(',')
+                    .map(forwarder::parse_upstream)
+                    .collect::<Result<Vec<_>, _>>()?;
+                zone_upstreams.push((domain.to_string(), addrs));
+            }
+            "--zone" => {
+                let zone = args.next().ok_or("Missing zone after --zone")?;
+                let (origin, path) = zone
+                    .split_once('=')
+                    .ok_or("Expected --zone <origin>=<zone file path>")?;
+                // Taken before reading, so that a change while reading is picked up later
+                let modified_at = get_modified_at(path);
+                zones.push(Zone::from_file(origin, path)?);
+                zone_files.push(ZoneFile {
+                    origin: origin.to_string(),
+                    path: path.to_string(),
+                    modified_at,
+                });
+            }
+            "--allow-transfer" => {
+                let addr = args
+                    .next()
+                    .ok_or("Missing address after --allow-transfer")?;
+                transfer_allow_list.push(addr.parse::<IpAddr>()?);
+            }
+            "--secondary-zone" => {
+                let zone = args.next().ok_or("Missing zone after --secondary-zone")?;
+                let (origin, primary) = zone
+                    .split_once('=')
+                    .ok_or("Expected --secondary-zone <origin>=<primary address>")?;
+                secondary_zones.push((origin.to_string(), forwarder::parse_upstream(primary)?));
+            }
+            "--notify" => {
+                let addr = args.next().ok_or("Missing address after --notify")?;
+                notify_list.push(forwarder::parse_upstream(&addr)?);
+            }
+            _ => return Err(format!("Unknown argument: {}", arg).into()),
+        }
+    }
+
+    let resolver = Arc::new(Resolver::new(&listen_addr, port)?);
+
+    if let Some(root_hints) = root_hints {
+        resolver.set_root_hints(root_hints);
+    }
+
+    // Secondaries are notified of the zones on startup too, in case they missed changes
+    for secondary in notify_list {
+        resolver.notify_zone_changes_to(secondary);
+    }
+
+    for zone in zones {
+        println!("Serving zone {}", zone.get_origin());
+        resolver.add_zone(zone);
+    }
+
+    for ip_addr in transfer_allow_list {
+        resolver.allow_zone_transfers_to(ip_addr);
+    }
+
+    for (origin, primary) in secondary_zones.iter() {
+        println!("Serving secondary zone {} from {}", origin, primary);
+        resolver.add_secondary_zone(origin, *primary);
+    }
+
+    for (domain, addrs) in zone_upstreams {
+        println!("Forwarding queries for {} to {:?}", domain, addrs);
+        resolver.add_conditional_forwarder(&domain, Forwarder::new(addrs, upstream_strategy));
+    }
+
+    if !upstreams.is_empty() {
+        println!("Forwarding queries to {:?}", upstreams);
+        resolver.set_forwarder(Forwarder::new(upstreams, upstream_strategy));
+    } else if let Err(e) = resolver.prime_root_hints() {
+        eprintln!("Failed to prime root hints, using the initial ones: {}", e);
+    }
+
+    println!(
+        "=== DNS server listening on {} port {} ===\n",
+        listen_addr, port
+    );
+
+    if !zone_files.is_empty() {
+        let watch_resolver = Arc::clone(&resolver);
+        thread::spawn(move || watch_zone_files(&watch_resolver, zone_files));
+    }
+
+    if !secondary_zones.is_empty() {
+        let refresh_resolver = Arc::clone(&resolver);
+        thread::spawn(move || {
+            while let Some(next_refresh_at) = refresh_resolver.refresh_secondary_zones() {
+                refresh_resolver.wait_for_secondary_refresh(next_refresh_at);
+            }
+        });
+    }
+
+    let tcp_resolver = Arc::clone(&resolver);
+    thread::spawn(move || {
+        let open_connections = Arc::new(AtomicUsize::new(0));
+
+        loop {
+            match tcp_resolver.accept_tcp_connection() {
+                // Dropping the stream closes the connection
+                Ok(_) if open_connections.load(Ordering::SeqCst) >= MAX_TCP_CONNECTIONS => {
+                    eprintln!("Closing TCP connection, too many are open");
+                }
+                Ok(stream) => {
+                    open_connections.fetch_add(1, Ordering::SeqCst);
+
+                    let tcp_resolver = Arc::clone(&tcp_resolver);
+                    let open_connections = Arc::clone(&open_connections);
+                    thread::spawn(move || {
+                        if let Err(e) = tcp_resolver.handle_tcp_connection(stream) {
+                            eprintln!("An error occurred over TCP: {}", e);
+                        }
+                        open_connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => eprintln!("An error occurred over TCP: {}", e),
+            }
+        }
+    });
 
-    println!("=== DNS server listening on port {} ===\n", port);
+    let udp_pool = ThreadPool::new(UDP_WORKER_COUNT, UDP_QUEUE_SIZE);
 
     loop {
-        match resolver.handle_query() {
-            Ok(_) => {}
+        match resolver.receive_query() {
+            Ok((req_packet, src_socket)) => {
+                let udp_resolver = Arc::clone(&resolver);
+                let is_queued = udp_pool.execute(move || {
+                    if let Err(e) = udp_resolver.respond_to_query(&req_packet, src_socket) {
+                        eprintln!("An error occurred: {}", e);
+                    }
+                });
+                // The client will retry, which beats answering long after it gave up
+                if !is_queued {
+                    eprintln!("Dropping query from {}, all workers are busy", src_socket);
+                }
+            }
             Err(e) => eprintln!("An error occurred: {}", e),
         }
     }
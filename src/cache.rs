@@ -0,0 +1,377 @@
+use crate::dns_packet::{QueryType, ResourceRecord, ReturnCode, INTERNET_CLASS};
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+type CacheKey = (String, QueryType, u16);
+
+const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    record: ResourceRecord,
+    expires_at: Instant,
+}
+
+// Proof of non-existence, kept for as long as the zone's SOA allows (RFC 2308)
+#[derive(Clone, Debug)]
+struct NegativeCacheEntry {
+    soa: ResourceRecord,
+    expires_at: Instant,
+}
+
+pub struct Cache {
+    entries: HashMap<CacheKey, Vec<CacheEntry>>,
+    nxdomain_entries: HashMap<String, NegativeCacheEntry>,
+    nodata_entries: HashMap<CacheKey, NegativeCacheEntry>,
+    // Counted per record set and per negative answer
+    max_entries: usize,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::with_max_entries(DEFAULT_MAX_ENTRIES)
+    }
+
+    pub fn with_max_entries(max_entries: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            nxdomain_entries: HashMap::new(),
+            nodata_entries: HashMap::new(),
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() + self.nxdomain_entries.len() + self.nodata_entries.len()
+    }
+
+    // A record set is only served while all of its records are fresh
+    fn set_expires_at(entries: &[CacheEntry]) -> Option<Instant> {
+        entries.iter().map(|entry| entry.expires_at).min()
+    }
+
+    fn remove_expiring_by(&mut self, instant: Instant) {
+        self.entries
+            .retain(|_, entries| entries.iter().all(|entry| entry.expires_at > instant));
+        self.nxdomain_entries
+            .retain(|_, entry| entry.expires_at > instant);
+        self.nodata_entries
+            .retain(|_, entry| entry.expires_at > instant);
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        if self.len() < self.max_entries {
+            return;
+        }
+
+        self.remove_expiring_by(now);
+        if self.len() < self.max_entries {
+            return;
+        }
+
+        // Evicting a tenth at once keeps a full cache from sorting on every insert
+        let target_len = self.max_entries - (self.max_entries / 10).max(1);
+        let mut expirations: Vec<Instant> = self
+            .entries
+            .values()
+            .filter_map(|entries| Self::set_expires_at(entries))
+            .chain(self.nxdomain_entries.values().map(|entry| entry.expires_at))
+            .chain(self.nodata_entries.values().map(|entry| entry.expires_at))
+            .collect();
+        expirations.sort_unstable();
+
+        // What would expire soonest anyway goes first
+        let excess = self.len() - target_len;
+        self.remove_expiring_by(expirations[excess - 1]);
+    }
+
+    fn key(domain: &str, qtype: QueryType) -> CacheKey {
+        (domain.to_lowercase(), qtype, INTERNET_CLASS)
+    }
+
+    pub fn insert(&mut self, records: &[ResourceRecord]) {
+        self.insert_at(records, Instant::now());
+    }
+
+    fn insert_at(&mut self, records: &[ResourceRecord], now: Instant) {
+        // Records are grouped into sets so that each set replaces its stale version as a whole
+        let mut record_sets: HashMap<CacheKey, Vec<CacheEntry>> = HashMap::new();
+
+        for record in records {
+            let ttl = record.get_ttl();
+            if ttl == 0 || record.get_query_type() == QueryType::OPT {
+                continue;
+            }
+
+            record_sets
+                .entry(Self::key(record.get_domain(), record.get_query_type()))
+                .or_default()
+                .push(CacheEntry {
+                    record: record.clone(),
+                    expires_at: now + Duration::from_secs(ttl as u64),
+                });
+        }
+
+        for (key, entries) in record_sets {
+            // The name and type exist after all
+            self.nxdomain_entries.remove(&key.0);
+            self.nodata_entries.remove(&key);
+
+            if !self.entries.contains_key(&key) {
+                self.make_room(now);
+            }
+            self.entries.insert(key, entries);
+        }
+    }
+
+    pub fn insert_negative(
+        &mut self,
+        domain: &str,
+        qtype: QueryType,
+        return_code: ReturnCode,
+        soa: &ResourceRecord,
+    ) {
+        self.insert_negative_at(domain, qtype, return_code, soa, Instant::now());
+    }
+
+    fn insert_negative_at(
+        &mut self,
+        domain: &str,
+        qtype: QueryType,
+        return_code: ReturnCode,
+        soa: &ResourceRecord,
+        now: Instant,
+    ) {
+        let ttl = match *soa {
+            ResourceRecord::SOA { minimum, ttl, .. } => minimum.min(ttl),
+            _ => return,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let entry = NegativeCacheEntry {
+            soa: soa.clone(),
+            expires_at: now + Duration::from_secs(ttl as u64),
+        };
+
+        self.make_room(now);
+
+        match return_code {
+            ReturnCode::NXDOMAIN => {
+                self.nxdomain_entries.insert(domain.to_lowercase(), entry);
+            }
+            ReturnCode::NOERROR => {
+                self.nodata_entries.insert(Self::key(domain, qtype), entry);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn lookup(&mut self, domain: &str, qtype: QueryType) -> Option<Vec<ResourceRecord>> {
+        self.lookup_at(domain, qtype, Instant::now())
+    }
+
+    fn lookup_at(
+        &mut self,
+        domain: &str,
+        qtype: QueryType,
+        now: Instant,
+    ) -> Option<Vec<ResourceRecord>> {
+        let key = Self::key(domain, qtype);
+
+        let entries = self.entries.get(&key)?;
+        if entries.iter().any(|entry| entry.expires_at <= now) {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        // Served records only live for as long as they have left in the cache
+        let records = entries
+            .iter()
+            .map(|entry| {
+                let mut record = entry.record.clone();
+                record.set_ttl((entry.expires_at - now).as_secs() as u32);
+                record
+            })
+            .collect();
+
+        Some(records)
+    }
+
+    // Returns the response code and the SOA record proving that the records don't exist
+    pub fn lookup_negative(
+        &mut self,
+        domain: &str,
+        qtype: QueryType,
+    ) -> Option<(ReturnCode, ResourceRecord)> {
+        self.lookup_negative_at(domain, qtype, Instant::now())
+    }
+
+    fn lookup_negative_at(
+        &mut self,
+        domain: &str,
+        qtype: QueryType,
+        now: Instant,
+    ) -> Option<(ReturnCode, ResourceRecord)> {
+        let key = Self::key(domain, qtype);
+
+        let (return_code, entry) = match self.nxdomain_entries.get(&key.0) {
+            Some(entry) => (ReturnCode::NXDOMAIN, entry),
+            None => (ReturnCode::NOERROR, self.nodata_entries.get(&key)?),
+        };
+
+        if entry.expires_at <= now {
+            match return_code {
+                ReturnCode::NXDOMAIN => self.nxdomain_entries.remove(&key.0),
+                _ => self.nodata_entries.remove(&key),
+            };
+            return None;
+        }
+
+        let mut soa = entry.soa.clone();
+        soa.set_ttl((entry.expires_at - now).as_secs() as u32);
+
+        Some((return_code, soa))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn insert_and_lookup_records() {
+        /* Arrange */
+        let mut cache = Cache::new();
+        let now = Instant::now();
+
+        let records = vec![
+            ResourceRecord::A {
+                domain: "google.com".to_string(),
+                ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+                ttl: 300,
+            },
+            ResourceRecord::A {
+                domain: "google.com".to_string(),
+                ip_addr: Ipv4Addr::new(142, 250, 185, 79),
+                ttl: 300,
+            },
+            ResourceRecord::NS {
+                domain: "google.com".to_string(),
+                host: "ns1.google.com".to_string(),
+                ttl: 0,
+            },
+        ];
+
+        /* Act */
+        cache.insert_at(&records, now);
+
+        let fresh_records = cache.lookup_at("Google.com", QueryType::A, now);
+        let aged_records =
+            cache.lookup_at("google.com", QueryType::A, now + Duration::from_secs(100));
+        let uncached_records = cache.lookup_at("google.com", QueryType::NS, now);
+        let expired_records =
+            cache.lookup_at("google.com", QueryType::A, now + Duration::from_secs(300));
+
+        /* Assert */
+        assert_eq!(Some(records[..2].to_vec()), fresh_records);
+
+        let aged_records = aged_records.unwrap();
+        assert_eq!(2, aged_records.len());
+        assert_eq!(200, aged_records[0].get_ttl());
+
+        assert_eq!(None, uncached_records);
+        assert_eq!(None, expired_records);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn insert_and_lookup_negative_answers() {
+        /* Arrange */
+        let mut cache = Cache::new();
+        let now = Instant::now();
+
+        let soa = ResourceRecord::SOA {
+            domain: "google.com".to_string(),
+            mname: "ns1.google.com".to_string(),
+            rname: "dns-admin.google.com".to_string(),
+            serial: 2021070100,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 300,
+        };
+
+        /* Act */
+        cache.insert_negative_at(
+            "nope.google.com",
+            QueryType::A,
+            ReturnCode::NXDOMAIN,
+            &soa,
+            now,
+        );
+        cache.insert_negative_at("google.com", QueryType::SRV, ReturnCode::NOERROR, &soa, now);
+
+        let nxdomain = cache.lookup_negative_at("nope.google.com", QueryType::AAAA, now);
+        let nodata = cache.lookup_negative_at("google.com", QueryType::SRV, now);
+        let other_type = cache.lookup_negative_at("google.com", QueryType::A, now);
+        let expired = cache.lookup_negative_at(
+            "nope.google.com",
+            QueryType::A,
+            now + Duration::from_secs(60),
+        );
+
+        /* Assert */
+        let (return_code, nxdomain_soa) = nxdomain.unwrap();
+        assert_eq!(ReturnCode::NXDOMAIN, return_code);
+        // The negative TTL is the lower of the SOA's minimum and its own TTL
+        assert_eq!(60, nxdomain_soa.get_ttl());
+
+        assert_eq!(ReturnCode::NOERROR, nodata.unwrap().0);
+        assert!(other_type.is_none());
+        assert!(expired.is_none());
+    }
+
+    #[test]
+    fn evict_when_full() {
+        /* Arrange */
+        let mut cache = Cache::with_max_entries(3);
+        let now = Instant::now();
+
+        let record = |domain: &str, ttl: u32| ResourceRecord::A {
+            domain: domain.to_string(),
+            ip_addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl,
+        };
+
+        /* Act */
+        cache.insert_at(
+            &[
+                record("a.example", 100),
+                record("b.example", 300),
+                record("c.example", 200),
+            ],
+            now,
+        );
+
+        // Nothing has expired yet, so the record expiring soonest is evicted
+        cache.insert_at(&[record("d.example", 300)], now);
+        let evicted = cache.lookup_at("a.example", QueryType::A, now);
+
+        // The expired record makes room
+        let later = now + Duration::from_secs(250);
+        cache.insert_at(&[record("e.example", 300)], later);
+
+        /* Assert */
+        assert!(evicted.is_none());
+        assert_eq!(3, cache.len());
+        for domain in ["b.example", "d.example", "e.example"] {
+            assert!(cache.lookup_at(domain, QueryType::A, later).is_some());
+        }
+    }
+}
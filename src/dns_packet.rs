@@ -1,10 +1,32 @@
 use crate::packet_buffer::PacketBuffer;
 
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub const INTERNET_CLASS: u16 = 1;
+pub const EDNS_VERSION: u8 = 0;
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+const IPV4_REVERSE_SUFFIX: &str = ".in-addr.arpa";
+const IPV6_REVERSE_SUFFIX: &str = ".ip6.arpa";
+
+// Whether the name is equal to or below the domain, compared label by label
+pub fn is_subdomain(name: &str, domain: &str) -> bool {
+    if domain.is_empty() || name == domain {
+        return true;
+    }
+
+    // A dot after an odd number of backslashes is escaped ("\."), which makes it part of a label
+    // rather than a separator
+    match name
+        .strip_suffix(domain)
+        .and_then(|prefix| prefix.strip_suffix('.'))
+    {
+        Some(label) => (label.len() - label.trim_end_matches('\\').len()) % 2 == 0,
+        None => false,
+    }
+}
 
 pub trait BufferIO {
     fn from_buffer(buffer: &mut PacketBuffer) -> Result<Self>
@@ -13,7 +35,7 @@ pub trait BufferIO {
     fn write_to_buffer(&mut self, buffer: &mut PacketBuffer) -> Result<()>;
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 #[repr(u16)]
 pub enum QueryType {
     /* NOTE: ideally this would use the num-derive crate and explicit discriminants
@@ -22,8 +44,15 @@ pub enum QueryType {
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
+    TXT,
     AAAA,
+    SRV,
+    OPT,
+    IXFR,
+    AXFR,
 }
 
 impl QueryType {
@@ -32,8 +61,15 @@ impl QueryType {
             Self::A => 1,
             Self::NS => 2,
             Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::PTR => 12,
             Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::SRV => 33,
+            Self::OPT => 41,
+            Self::IXFR => 251,
+            Self::AXFR => 252,
             Self::UNKNOWN(num) => num,
         }
     }
@@ -43,8 +79,15 @@ impl QueryType {
             1 => Self::A,
             2 => Self::NS,
             5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
             15 => Self::MX,
+            16 => Self::TXT,
             28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
+            251 => Self::IXFR,
+            252 => Self::AXFR,
             _ => Self::UNKNOWN(num),
         }
     }
@@ -65,6 +108,71 @@ impl Query {
             class: INTERNET_CLASS,
         }
     }
+
+    pub fn new_reverse(ip_addr: IpAddr) -> Self {
+        let qname = match ip_addr {
+            IpAddr::V4(ip_addr) => {
+                let mut labels: Vec<String> =
+                    ip_addr.octets().iter().map(|o| o.to_string()).collect();
+                labels.reverse();
+
+                labels.join(".") + IPV4_REVERSE_SUFFIX
+            }
+            IpAddr::V6(ip_addr) => {
+                // Each nibble is its own label, starting from the least significant one
+                let labels: Vec<String> = ip_addr
+                    .octets()
+                    .iter()
+                    .rev()
+                    .flat_map(|o| vec![o & 0x0F, o >> 4])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect();
+
+                labels.join(".") + IPV6_REVERSE_SUFFIX
+            }
+        };
+
+        Self::new(qname, QueryType::PTR)
+    }
+
+    pub fn get_reverse_ip_addr(&self) -> Option<IpAddr> {
+        if let Some(labels) = self.qname.strip_suffix(IPV4_REVERSE_SUFFIX) {
+            let mut octets = labels
+                .split('.')
+                .map(|label| label.parse::<u8>().ok())
+                .collect::<Option<Vec<u8>>>()?;
+            if octets.len() != 4 {
+                return None;
+            }
+            octets.reverse();
+
+            return Some(IpAddr::V4(Ipv4Addr::new(
+                octets[0], octets[1], octets[2], octets[3],
+            )));
+        }
+
+        if let Some(labels) = self.qname.strip_suffix(IPV6_REVERSE_SUFFIX) {
+            let nibbles = labels
+                .split('.')
+                .map(|label| match label.len() {
+                    1 => u8::from_str_radix(label, 16).ok(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<u8>>>()?;
+            if nibbles.len() != 32 {
+                return None;
+            }
+
+            let mut octets = [0; 16];
+            for (i, pair) in nibbles.chunks(2).rev().enumerate() {
+                octets[i] = (pair[1] << 4) | pair[0];
+            }
+
+            return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+        }
+
+        None
+    }
 }
 
 impl BufferIO for Query {
@@ -84,14 +192,70 @@ impl BufferIO for Query {
         Ok(())
     }
 }
-#[derive(Copy, Clone, Debug, PartialEq, FromPrimitive)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Opcode {
+    UNKNOWN(u8),
+    QUERY,
+    NOTIFY,
+}
+
+impl Opcode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            Self::QUERY => 0,
+            Self::NOTIFY => 4,
+            Self::UNKNOWN(num) => num,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Self {
+        match num {
+            0 => Self::QUERY,
+            4 => Self::NOTIFY,
+            _ => Self::UNKNOWN(num),
+        }
+    }
+}
+
+// Codes above 15 are split between the header and the OPT record (RFC 6891 section 6.1.3)
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ReturnCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    UNKNOWN(u16),
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    BADVERS,
+}
+
+impl ReturnCode {
+    pub fn to_num(self) -> u16 {
+        match self {
+            Self::NOERROR => 0,
+            Self::FORMERR => 1,
+            Self::SERVFAIL => 2,
+            Self::NXDOMAIN => 3,
+            Self::NOTIMP => 4,
+            Self::REFUSED => 5,
+            Self::BADVERS => 16,
+            Self::UNKNOWN(num) => num,
+        }
+    }
+
+    pub fn from_num(num: u16) -> Self {
+        match num {
+            0 => Self::NOERROR,
+            1 => Self::FORMERR,
+            2 => Self::SERVFAIL,
+            3 => Self::NXDOMAIN,
+            4 => Self::NOTIMP,
+            5 => Self::REFUSED,
+            16 => Self::BADVERS,
+            _ => Self::UNKNOWN(num),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -99,7 +263,7 @@ pub struct Header {
     pub id: u16,
 
     pub response: bool,
-    pub opcode: u8, // 4 bits
+    pub opcode: Opcode, // 4 bits
     pub authoritative_answer: bool,
     pub truncated_message: bool,
     pub recursion_desired: bool,
@@ -108,7 +272,7 @@ pub struct Header {
     pub z: bool,
     pub authenticated_data: bool,
     pub checking_disabled: bool,
-    pub return_code: ReturnCode, // 4 bits
+    pub return_code: ReturnCode, // 4 bits, or 12 along with the OPT record
 
     pub queries_total: u16,
     pub answer_rr_total: u16,
@@ -122,7 +286,7 @@ impl Header {
             id: 0,
 
             response: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             authoritative_answer: false,
             truncated_message: false,
             recursion_desired: false,
@@ -152,7 +316,7 @@ impl BufferIO for Header {
         let flags_b2 = (flags & 0xFF) as u8;
 
         header.response = (flags_b1 & (1 << 7)) > 0;
-        header.opcode = (flags_b1 >> 3) & 0x0F;
+        header.opcode = Opcode::from_num((flags_b1 >> 3) & 0x0F);
         header.authoritative_answer = (flags_b1 & (1 << 2)) > 0;
         header.truncated_message = (flags_b1 & (1 << 1)) > 0;
         header.recursion_desired = (flags_b1 & 1) > 0;
@@ -161,7 +325,7 @@ impl BufferIO for Header {
         header.z = (flags_b2 & (1 << 6)) > 0;
         header.authenticated_data = (flags_b2 & (1 << 5)) > 0;
         header.checking_disabled = (flags_b2 & (1 << 4)) > 0;
-        header.return_code = num::FromPrimitive::from_u8(flags_b2 & 0x0F).unwrap();
+        header.return_code = ReturnCode::from_num((flags_b2 & 0x0F) as u16);
 
         header.queries_total = buffer.read_u16()?;
         header.answer_rr_total = buffer.read_u16()?;
@@ -175,17 +339,17 @@ impl BufferIO for Header {
         buffer.write_u16(self.id)?;
 
         let mut flags_b1 = (self.response as u8) << 7;
-        flags_b1 |= self.opcode << 3;
+        flags_b1 |= self.opcode.to_num() << 3;
         flags_b1 |= (self.authoritative_answer as u8) << 2;
         flags_b1 |= (self.truncated_message as u8) << 1;
-        flags_b1 |= (self.recursion_desired as u8) as u8;
+        flags_b1 |= self.recursion_desired as u8;
         buffer.write_u8(flags_b1)?;
 
         let mut flags_b2 = (self.recursion_available as u8) << 7;
         flags_b2 |= (self.z as u8) << 6;
         flags_b2 |= (self.authenticated_data as u8) << 5;
         flags_b2 |= (self.checking_disabled as u8) << 4;
-        flags_b2 |= self.return_code as u8;
+        flags_b2 |= (self.return_code.to_num() & 0x0F) as u8;
         buffer.write_u8(flags_b2)?;
 
         buffer.write_u16(self.queries_total)?;
@@ -217,6 +381,63 @@ impl Packet {
         }
     }
 
+    // Whether this packet is a response to the given request, judging by its ID and question
+    pub fn is_response_to(&self, request: &Packet) -> bool {
+        self.header.response
+            && self.header.id == request.header.id
+            && self.queries.len() == request.queries.len()
+            && self
+                .queries
+                .iter()
+                .zip(request.queries.iter())
+                .all(|(res_query, req_query)| {
+                    res_query.qtype == req_query.qtype
+                        && res_query.qname.eq_ignore_ascii_case(&req_query.qname)
+                })
+    }
+
+    // Drops the least important record so that the packet fits a smaller message,
+    // returning false if there are no records left to drop
+    pub fn drop_record(&mut self) -> bool {
+        // The OPT record is kept as it tells the client how much it can send in the retry
+        if let Some(i) = self
+            .additional_records
+            .iter()
+            .rposition(|record| !matches!(record, ResourceRecord::OPT { .. }))
+        {
+            self.additional_records.remove(i);
+            return true;
+        }
+
+        if self.authoritative_records.pop().is_some() || self.answer_records.pop().is_some() {
+            self.header.truncated_message = true;
+            return true;
+        }
+
+        false
+    }
+
+    pub fn get_opt_record(&self) -> Option<&ResourceRecord> {
+        self.additional_records
+            .iter()
+            .find(|record| matches!(record, ResourceRecord::OPT { .. }))
+    }
+
+    pub fn get_max_udp_payload_size(&self) -> u16 {
+        match self.get_opt_record() {
+            Some(ResourceRecord::OPT {
+                udp_payload_size, ..
+            }) => (*udp_payload_size).max(MIN_UDP_PAYLOAD_SIZE),
+            _ => MIN_UDP_PAYLOAD_SIZE,
+        }
+    }
+
+    pub fn get_soa_record(&self) -> Option<&ResourceRecord> {
+        self.authoritative_records
+            .iter()
+            .find(|record| matches!(record, ResourceRecord::SOA { .. }))
+    }
+
     pub fn get_answer_a_records(&self) -> Vec<&Ipv4Addr> {
         self.answer_records
             .iter()
@@ -227,6 +448,14 @@ impl Packet {
             .collect()
     }
 
+    // Returns the addresses from both A and AAAA answers
+    pub fn get_answer_ip_addrs(&self) -> Vec<IpAddr> {
+        self.answer_records
+            .iter()
+            .filter_map(ResourceRecord::get_ip_addr)
+            .collect()
+    }
+
     fn get_ns_domain_host_iter<'a>(
         &'a self,
         qname: &'a str,
@@ -238,7 +467,13 @@ impl Packet {
                 _ => None,
             })
             // Only include domains authoritative to the query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| is_subdomain(qname, domain))
+    }
+
+    pub fn get_referral_zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns_domain_host_iter(qname)
+            .map(|(domain, _)| domain)
+            .last()
     }
 
     pub fn get_ns_hosts<'a>(&'a self, qname: &'a str) -> Vec<&'a str> {
@@ -247,17 +482,14 @@ impl Packet {
             .collect()
     }
 
-    pub fn get_ns_from_additional_records(&self, qname: &str) -> Vec<&Ipv4Addr> {
+    // Both A and AAAA glue is returned, so that nameservers can be reached over either protocol
+    pub fn get_ns_from_additional_records(&self, qname: &str) -> Vec<IpAddr> {
         self.get_ns_domain_host_iter(qname)
             .flat_map(|(_, host)| {
                 self.additional_records
                     .iter()
-                    .filter_map(move |record| match record {
-                        ResourceRecord::A {
-                            domain, ip_addr, ..
-                        } if domain == host => Some(ip_addr),
-                        _ => None,
-                    })
+                    .filter(move |record| record.get_domain() == host)
+                    .filter_map(ResourceRecord::get_ip_addr)
             })
             .collect()
     }
@@ -286,6 +518,11 @@ impl BufferIO for Packet {
             packet.additional_records.push(record);
         }
 
+        if let Some(&ResourceRecord::OPT { extended_rcode, .. }) = packet.get_opt_record() {
+            let return_code = (extended_rcode as u16) << 4 | packet.header.return_code.to_num();
+            packet.header.return_code = ReturnCode::from_num(return_code);
+        }
+
         Ok(packet)
     }
 
@@ -295,6 +532,13 @@ impl BufferIO for Packet {
         self.header.authoritative_rr_total = self.authoritative_records.len() as u16;
         self.header.additional_rr_total = self.additional_records.len() as u16;
 
+        let return_code = self.header.return_code;
+        for rec in &mut self.additional_records {
+            if let ResourceRecord::OPT { extended_rcode, .. } = rec {
+                *extended_rcode = (return_code.to_num() >> 4) as u8;
+            }
+        }
+
         self.header.write_to_buffer(buffer)?;
 
         for query in &mut self.queries {
@@ -314,12 +558,18 @@ impl BufferIO for Packet {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd)]
 pub enum ResourceRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A {
@@ -337,20 +587,154 @@ pub enum ResourceRecord {
         host: String,
         ttl: u32,
     },
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
     MX {
         domain: String,
         priority: u16,
         exchange: String,
         ttl: u32,
     },
+    TXT {
+        domain: String,
+        data: Vec<Vec<u8>>, // One entry per character-string
+        ttl: u32,
+    },
     AAAA {
         domain: String,
         ip_addr: Ipv6Addr,
         ttl: u32,
     },
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+        ttl: u32,
+    },
+    OPT {
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    },
 }
 
 impl ResourceRecord {
+    pub fn new_opt(udp_payload_size: u16) -> Self {
+        ResourceRecord::OPT {
+            udp_payload_size,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn get_domain(&self) -> &str {
+        match self {
+            ResourceRecord::UNKNOWN { domain, .. }
+            | ResourceRecord::A { domain, .. }
+            | ResourceRecord::NS { domain, .. }
+            | ResourceRecord::CNAME { domain, .. }
+            | ResourceRecord::SOA { domain, .. }
+            | ResourceRecord::PTR { domain, .. }
+            | ResourceRecord::MX { domain, .. }
+            | ResourceRecord::TXT { domain, .. }
+            | ResourceRecord::AAAA { domain, .. }
+            | ResourceRecord::SRV { domain, .. } => domain,
+            ResourceRecord::OPT { .. } => "",
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match self {
+            ResourceRecord::UNKNOWN { domain, .. }
+            | ResourceRecord::A { domain, .. }
+            | ResourceRecord::NS { domain, .. }
+            | ResourceRecord::CNAME { domain, .. }
+            | ResourceRecord::SOA { domain, .. }
+            | ResourceRecord::PTR { domain, .. }
+            | ResourceRecord::MX { domain, .. }
+            | ResourceRecord::TXT { domain, .. }
+            | ResourceRecord::AAAA { domain, .. }
+            | ResourceRecord::SRV { domain, .. } => *domain = new_domain.to_string(),
+            ResourceRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn get_query_type(&self) -> QueryType {
+        match self {
+            ResourceRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(*qtype),
+            ResourceRecord::A { .. } => QueryType::A,
+            ResourceRecord::NS { .. } => QueryType::NS,
+            ResourceRecord::CNAME { .. } => QueryType::CNAME,
+            ResourceRecord::SOA { .. } => QueryType::SOA,
+            ResourceRecord::PTR { .. } => QueryType::PTR,
+            ResourceRecord::MX { .. } => QueryType::MX,
+            ResourceRecord::TXT { .. } => QueryType::TXT,
+            ResourceRecord::AAAA { .. } => QueryType::AAAA,
+            ResourceRecord::SRV { .. } => QueryType::SRV,
+            ResourceRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match self {
+            ResourceRecord::UNKNOWN { ttl, .. }
+            | ResourceRecord::A { ttl, .. }
+            | ResourceRecord::NS { ttl, .. }
+            | ResourceRecord::CNAME { ttl, .. }
+            | ResourceRecord::SOA { ttl, .. }
+            | ResourceRecord::PTR { ttl, .. }
+            | ResourceRecord::MX { ttl, .. }
+            | ResourceRecord::TXT { ttl, .. }
+            | ResourceRecord::AAAA { ttl, .. }
+            | ResourceRecord::SRV { ttl, .. } => *ttl,
+            ResourceRecord::OPT { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            ResourceRecord::UNKNOWN { ttl, .. }
+            | ResourceRecord::A { ttl, .. }
+            | ResourceRecord::NS { ttl, .. }
+            | ResourceRecord::CNAME { ttl, .. }
+            | ResourceRecord::SOA { ttl, .. }
+            | ResourceRecord::PTR { ttl, .. }
+            | ResourceRecord::MX { ttl, .. }
+            | ResourceRecord::TXT { ttl, .. }
+            | ResourceRecord::AAAA { ttl, .. }
+            | ResourceRecord::SRV { ttl, .. } => *ttl = new_ttl,
+            ResourceRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn get_ip_addr(&self) -> Option<IpAddr> {
+        match *self {
+            ResourceRecord::A { ip_addr, .. } => Some(IpAddr::V4(ip_addr)),
+            ResourceRecord::AAAA { ip_addr, .. } => Some(IpAddr::V6(ip_addr)),
+            _ => None,
+        }
+    }
+
     fn write_common_fields(
         &self,
         buffer: &mut PacketBuffer,
@@ -366,21 +750,28 @@ impl ResourceRecord {
         Ok(())
     }
 
-    fn write_compressed_name_with_size(&self, buffer: &mut PacketBuffer, name: &str) -> Result<()> {
+    fn write_data_with_size<F>(&self, buffer: &mut PacketBuffer, write_data: F) -> Result<()>
+    where
+        F: FnOnce(&mut PacketBuffer) -> Result<()>,
+    {
         // Skip over size field
-        let name_size_field_len = 2;
-        buffer.step(name_size_field_len);
+        let data_size_field_len = 2;
+        buffer.step(data_size_field_len);
 
-        // Write name and get its size
-        let name_start_pos = buffer.pos();
-        buffer.write_compressed_name(name)?;
-        let name_size = buffer.pos() - name_start_pos;
+        // Write data and get its size
+        let data_start_pos = buffer.pos();
+        write_data(buffer)?;
+        let data_size = buffer.pos() - data_start_pos;
 
         // Write previously skipped size field
-        buffer.set_u16(name_start_pos - name_size_field_len, name_size as u16)?;
+        buffer.set_u16(data_start_pos - data_size_field_len, data_size as u16)?;
 
         Ok(())
     }
+
+    fn write_compressed_name_with_size(&self, buffer: &mut PacketBuffer, name: &str) -> Result<()> {
+        self.write_data_with_size(buffer, |buffer| buffer.write_compressed_name(name))
+    }
 }
 
 impl BufferIO for ResourceRecord {
@@ -389,7 +780,7 @@ impl BufferIO for ResourceRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _class = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
@@ -443,6 +834,32 @@ impl BufferIO for ResourceRecord {
 
                 Ok(ResourceRecord::CNAME { domain, host, ttl })
             }
+            QueryType::SOA => {
+                let mname = buffer.read_compressed_name()?;
+                let rname = buffer.read_compressed_name()?;
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(ResourceRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let host = buffer.read_compressed_name()?;
+
+                Ok(ResourceRecord::PTR { domain, host, ttl })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let exchange = buffer.read_compressed_name()?;
@@ -454,13 +871,62 @@ impl BufferIO for ResourceRecord {
                     ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize);
+            QueryType::TXT => {
+                let data_end_pos = buffer.pos() + data_len as usize;
+
+                let mut data = Vec::new();
+                while buffer.pos() < data_end_pos {
+                    data.push(buffer.read_character_string()?);
+                }
+
+                Ok(ResourceRecord::TXT { domain, data, ttl })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let target = buffer.read_compressed_name()?;
+
+                Ok(ResourceRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    target,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                // The class and TTL fields are repurposed by EDNS (RFC 6891)
+                let data_end_pos = buffer.pos() + data_len as usize;
+
+                let mut options = Vec::new();
+                while buffer.pos() < data_end_pos {
+                    let code = buffer.read_u16()?;
+                    let option_len = buffer.read_u16()?;
+                    let data = buffer.read_bytes(option_len as usize)?;
+
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(ResourceRecord::OPT {
+                    udp_payload_size: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: ((ttl >> 16) & 0xFF) as u8,
+                    dnssec_ok: (ttl & (1 << 15)) > 0,
+                    options,
+                })
+            }
+            // Zone transfer types only appear in queries, so records claiming to be of them are
+            // opaque too
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR => {
+                // Keep the data opaque so that it can be passed on verbatim (RFC 3597)
+                let data = buffer.read_bytes(data_len as usize)?;
 
                 Ok(ResourceRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
@@ -500,6 +966,38 @@ impl BufferIO for ResourceRecord {
 
                 self.write_compressed_name_with_size(buffer, host)?;
             }
+            ResourceRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                self.write_common_fields(buffer, domain, QueryType::SOA, ttl)?;
+
+                self.write_data_with_size(buffer, |buffer| {
+                    buffer.write_compressed_name(mname)?;
+                    buffer.write_compressed_name(rname)?;
+                    buffer.write_u32(serial)?;
+                    buffer.write_u32(refresh)?;
+                    buffer.write_u32(retry)?;
+                    buffer.write_u32(expire)?;
+                    buffer.write_u32(minimum)
+                })?;
+            }
+            ResourceRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                self.write_common_fields(buffer, domain, QueryType::PTR, ttl)?;
+
+                self.write_compressed_name_with_size(buffer, host)?;
+            }
             ResourceRecord::MX {
                 ref domain,
                 priority,
@@ -508,18 +1006,25 @@ impl BufferIO for ResourceRecord {
             } => {
                 self.write_common_fields(buffer, domain, QueryType::MX, ttl)?;
 
-                // Skip over size field
-                let name_size_field_len = 2;
-                buffer.step(name_size_field_len);
+                self.write_data_with_size(buffer, |buffer| {
+                    buffer.write_u16(priority)?;
+                    buffer.write_compressed_name(exchange)
+                })?;
+            }
+            ResourceRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                self.write_common_fields(buffer, domain, QueryType::TXT, ttl)?;
 
-                // Write name and get its size
-                let name_start_pos = buffer.pos();
-                buffer.write_u16(priority)?;
-                buffer.write_compressed_name(exchange)?;
-                let name_size = buffer.pos() - name_start_pos;
+                self.write_data_with_size(buffer, |buffer| {
+                    for bytes in data {
+                        buffer.write_character_string(bytes)?;
+                    }
 
-                // Write previously skipped size field
-                buffer.set_u16(name_start_pos - name_size_field_len, name_size as u16)?;
+                    Ok(())
+                })?;
             }
             ResourceRecord::AAAA {
                 ref domain,
@@ -534,8 +1039,59 @@ impl BufferIO for ResourceRecord {
                     buffer.write_u16(*segment_u16)?;
                 }
             }
-            ResourceRecord::UNKNOWN { .. } => {
-                println!("Skipping unknown record: {:?}", self);
+            ResourceRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref target,
+                ttl,
+            } => {
+                self.write_common_fields(buffer, domain, QueryType::SRV, ttl)?;
+
+                self.write_data_with_size(buffer, |buffer| {
+                    buffer.write_u16(priority)?;
+                    buffer.write_u16(weight)?;
+                    buffer.write_u16(port)?;
+                    buffer.write_uncompressed_name(target)
+                })?;
+            }
+            ResourceRecord::OPT {
+                udp_payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                ref options,
+            } => {
+                let root_domain = "";
+                buffer.write_compressed_name(root_domain)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(udp_payload_size)?;
+
+                let mut ttl = (extended_rcode as u32) << 24;
+                ttl |= (version as u32) << 16;
+                ttl |= (dnssec_ok as u32) << 15;
+                buffer.write_u32(ttl)?;
+
+                self.write_data_with_size(buffer, |buffer| {
+                    for option in options {
+                        buffer.write_u16(option.code)?;
+                        buffer.write_u16(option.data.len() as u16)?;
+                        buffer.write_bytes(&option.data)?;
+                    }
+
+                    Ok(())
+                })?;
+            }
+            ResourceRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                self.write_common_fields(buffer, domain, QueryType::UNKNOWN(qtype), ttl)?;
+
+                self.write_data_with_size(buffer, |buffer| buffer.write_bytes(data))?;
             }
         }
 
@@ -547,6 +1103,19 @@ impl BufferIO for ResourceRecord {
 mod tests {
     use super::*;
 
+    #[test]
+    fn compare_names_by_label() {
+        /* Assert */
+        assert!(is_subdomain("www.example.com", "example.com"));
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("example.com", ""));
+        assert!(!is_subdomain("notexample.com", "example.com"));
+        // Escaped dots are part of a label, unless the backslash before them is escaped itself
+        assert!(!is_subdomain("www\\.example.com", "example.com"));
+        assert!(is_subdomain("www\\\\.example.com", "example.com"));
+        assert!(!is_subdomain("www\\\\\\.example.com", "example.com"));
+    }
+
     #[test]
     fn write_and_read_packet() {
         /* Arrange */
@@ -578,4 +1147,308 @@ mod tests {
         assert_eq!(packet.answer_records[0], parsed_packet.answer_records[0]);
         assert_eq!(packet.answer_records[1], parsed_packet.answer_records[1]);
     }
+
+    #[test]
+    fn write_and_read_txt_records() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        packet.answer_records.push(ResourceRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec![b"v=spf1 -all".to_vec(), Vec::new()],
+            ttl: 64,
+        });
+        // Neither binary data nor a multi-byte character cut at the end of a string is text
+        let mut split_character = vec![b'a'; 254];
+        split_character.push("é".as_bytes()[0]);
+        packet.answer_records.push(ResourceRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec![vec![0x00, 0xFF, 0x80], split_character],
+            ttl: 64,
+        });
+
+        let mut oversized_packet = Packet::new();
+        oversized_packet.answer_records.push(ResourceRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec![vec![b'a'; 256]],
+            ttl: 64,
+        });
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        let oversized_result = oversized_packet.write_to_buffer(&mut PacketBuffer::new());
+
+        /* Assert */
+        assert_eq!(packet.answer_records, parsed_packet.answer_records);
+        assert!(oversized_result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_soa_record() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        packet.authoritative_records.push(ResourceRecord::SOA {
+            domain: "google.com".to_string(),
+            mname: "ns1.google.com".to_string(),
+            rname: "dns-admin.google.com".to_string(),
+            serial: 2021070100,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 60,
+        });
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        assert_eq!(
+            packet.authoritative_records[0],
+            parsed_packet.authoritative_records[0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_srv_record() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        packet.queries.push(Query::new(
+            "_ldap._tcp.google.com".to_string(),
+            QueryType::SRV,
+        ));
+        packet.answer_records.push(ResourceRecord::SRV {
+            domain: "_ldap._tcp.google.com".to_string(),
+            priority: 5,
+            weight: 10,
+            port: 389,
+            target: "ldap.google.com".to_string(),
+            ttl: 300,
+        });
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        assert_eq!(packet.queries[0], parsed_packet.queries[0]);
+        assert_eq!(packet.answer_records[0], parsed_packet.answer_records[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_query_names() {
+        /* Arrange */
+        let ipv4_addr = IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4));
+        let ipv6_addr = IpAddr::V6("2001:4860:4860::8844".parse::<Ipv6Addr>().unwrap());
+
+        /* Act */
+        let ipv4_query = Query::new_reverse(ipv4_addr);
+        let ipv6_query = Query::new_reverse(ipv6_addr);
+
+        /* Assert */
+        assert_eq!(QueryType::PTR, ipv4_query.qtype);
+        assert_eq!("4.4.8.8.in-addr.arpa", ipv4_query.qname);
+        assert_eq!(Some(ipv4_addr), ipv4_query.get_reverse_ip_addr());
+
+        assert_eq!(
+            "4.4.8.8.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.6.8.4.0.6.8.4.1.0.0.2.ip6.arpa",
+            ipv6_query.qname
+        );
+        assert_eq!(Some(ipv6_addr), ipv6_query.get_reverse_ip_addr());
+
+        let non_reverse_query = Query::new("google.com".to_string(), QueryType::PTR);
+        assert_eq!(None, non_reverse_query.get_reverse_ip_addr());
+    }
+
+    #[test]
+    fn write_and_read_ptr_record() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        let query = Query::new_reverse(IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)));
+        packet.answer_records.push(ResourceRecord::PTR {
+            domain: query.qname.clone(),
+            host: "dns.google".to_string(),
+            ttl: 300,
+        });
+        packet.queries.push(query);
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        assert_eq!(packet.queries[0], parsed_packet.queries[0]);
+        assert_eq!(packet.answer_records[0], parsed_packet.answer_records[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_unknown_record() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        let caa_qtype = 257;
+        packet.answer_records.push(ResourceRecord::UNKNOWN {
+            domain: "google.com".to_string(),
+            qtype: caa_qtype,
+            data: b"\x00\x05issuepki.goog".to_vec(),
+            ttl: 86400,
+        });
+        packet.answer_records.push(ResourceRecord::A {
+            domain: "google.com".to_string(),
+            ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+            ttl: 300,
+        });
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        assert_eq!(2, parsed_packet.header.answer_rr_total);
+        assert_eq!(packet.answer_records[0], parsed_packet.answer_records[0]);
+        assert_eq!(packet.answer_records[1], parsed_packet.answer_records[1]);
+        assert_eq!(
+            QueryType::UNKNOWN(caa_qtype),
+            QueryType::from_num(caa_qtype)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_opt_record() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        packet
+            .queries
+            .push(Query::new("google.com".to_string(), QueryType::A));
+        packet.additional_records.push(ResourceRecord::OPT {
+            udp_payload_size: 4096,
+            extended_rcode: 0,
+            version: EDNS_VERSION,
+            dnssec_ok: true,
+            options: vec![EdnsOption {
+                code: 10, // Cookie
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }],
+        });
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        assert_eq!(
+            packet.additional_records[0],
+            parsed_packet.additional_records[0]
+        );
+        assert_eq!(4096, parsed_packet.get_max_udp_payload_size());
+        assert_eq!(
+            MIN_UDP_PAYLOAD_SIZE,
+            Packet::new().get_max_udp_payload_size()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fold_extended_return_code() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+        packet.header.return_code = ReturnCode::BADVERS;
+        packet
+            .additional_records
+            .push(ResourceRecord::new_opt(MIN_UDP_PAYLOAD_SIZE));
+
+        /* Act */
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let header = Header::from_buffer(&mut buffer)?;
+
+        buffer.seek(0);
+        let parsed_packet = Packet::from_buffer(&mut buffer)?;
+
+        /* Assert */
+        // Only the lower 4 bits fit in the header, the rest go in the OPT record
+        assert_eq!(ReturnCode::NOERROR, header.return_code);
+        assert_eq!(ReturnCode::BADVERS, parsed_packet.header.return_code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_ns_glue_for_both_protocols() -> Result<()> {
+        /* Arrange */
+        let mut packet = Packet::new();
+
+        packet.authoritative_records.push(ResourceRecord::NS {
+            domain: "google.com".to_string(),
+            host: "ns1.google.com".to_string(),
+            ttl: 300,
+        });
+        packet.additional_records.push(ResourceRecord::A {
+            domain: "ns1.google.com".to_string(),
+            ip_addr: Ipv4Addr::new(216, 239, 32, 10),
+            ttl: 300,
+        });
+        packet.additional_records.push(ResourceRecord::AAAA {
+            domain: "ns1.google.com".to_string(),
+            ip_addr: "2001:4860:4802:32::a".parse::<Ipv6Addr>()?,
+            ttl: 300,
+        });
+        packet.additional_records.push(ResourceRecord::A {
+            domain: "ns2.google.com".to_string(),
+            ip_addr: Ipv4Addr::new(216, 239, 34, 10),
+            ttl: 300,
+        });
+
+        /* Act */
+        let glue_ip_addrs = packet.get_ns_from_additional_records("www.google.com");
+
+        /* Assert */
+        assert_eq!(
+            vec![
+                IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10)),
+                IpAddr::V6("2001:4860:4802:32::a".parse::<Ipv6Addr>()?),
+            ],
+            glue_ip_addrs
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,297 @@
+use crate::dns_packet::is_subdomain;
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const DNS_PORT: u16 = 53;
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const UNHEALTHY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RTT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpstreamStrategy {
+    Ordered,    // Always start with the first upstream
+    RoundRobin, // Start with the next upstream on every lookup
+    Fastest,    // Start with the upstream with the lowest smoothed RTT
+}
+
+impl FromStr for UpstreamStrategy {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "ordered" => Ok(UpstreamStrategy::Ordered),
+            "round-robin" => Ok(UpstreamStrategy::RoundRobin),
+            "fastest" => Ok(UpstreamStrategy::Fastest),
+            _ => Err(format!("Unknown upstream strategy: {}", name).into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct UpstreamHealth {
+    srtt: Option<Duration>,
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    fn is_healthy_at(&self, now: Instant) -> bool {
+        match self.unhealthy_until {
+            Some(unhealthy_until) => unhealthy_until <= now,
+            None => true,
+        }
+    }
+}
+
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    strategy: UpstreamStrategy,
+    health: Mutex<Vec<UpstreamHealth>>,
+    next_upstream: AtomicUsize,
+}
+
+// Accepts both "ip" and "ip:port", defaulting to the DNS port
+pub fn parse_upstream(upstream: &str) -> Result<SocketAddr> {
+    match upstream.parse::<SocketAddr>() {
+        Ok(socket_addr) => Ok(socket_addr),
+        Err(_) => Ok(SocketAddr::new(upstream.parse::<IpAddr>()?, DNS_PORT)),
+    }
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<SocketAddr>, strategy: UpstreamStrategy) -> Self {
+        let health = vec![UpstreamHealth::default(); upstreams.len()];
+
+        Forwarder {
+            upstreams,
+            strategy,
+            health: Mutex::new(health),
+            next_upstream: AtomicUsize::new(0),
+        }
+    }
+
+    // Lists the upstreams in the order they should be tried in
+    pub fn get_upstreams(&self) -> Vec<SocketAddr> {
+        self.get_upstreams_at(Instant::now())
+    }
+
+    fn get_upstreams_at(&self, now: Instant) -> Vec<SocketAddr> {
+        if self.upstreams.is_empty() {
+            return Vec::new();
+        }
+
+        let health = self.health.lock().unwrap();
+        let mut indices: Vec<usize> = (0..self.upstreams.len()).collect();
+
+        match self.strategy {
+            UpstreamStrategy::Ordered => {}
+            UpstreamStrategy::RoundRobin => {
+                let start = self.next_upstream.fetch_add(1, Ordering::Relaxed) % indices.len();
+                indices.rotate_left(start);
+            }
+            UpstreamStrategy::Fastest => {
+                // Upstreams without measurements come first, so that they get measured
+                indices.sort_by_key(|&i| health[i].srtt.unwrap_or_default());
+            }
+        }
+
+        // Unhealthy upstreams are still tried as a last resort
+        indices.sort_by_key(|&i| !health[i].is_healthy_at(now));
+
+        indices.into_iter().map(|i| self.upstreams[i]).collect()
+    }
+
+    pub fn record_success(&self, upstream: SocketAddr, rtt: Duration) {
+        let mut health = self.health.lock().unwrap();
+        let upstream_health = match self.position(upstream) {
+            Some(i) => &mut health[i],
+            None => return,
+        };
+
+        // Smoothed the same way as TCP's RTT estimate (RFC 6298)
+        upstream_health.srtt = Some(match upstream_health.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        upstream_health.consecutive_failures = 0;
+        upstream_health.unhealthy_until = None;
+    }
+
+    pub fn record_failure(&self, upstream: SocketAddr) {
+        self.record_failure_at(upstream, Instant::now());
+    }
+
+    fn record_failure_at(&self, upstream: SocketAddr, now: Instant) {
+        let mut health = self.health.lock().unwrap();
+        let upstream_health = match self.position(upstream) {
+            Some(i) => &mut health[i],
+            None => return,
+        };
+
+        // Failures count as slow responses, so that the fastest upstreams are tried first
+        upstream_health.srtt = Some(match upstream_health.srtt {
+            Some(srtt) => (srtt * 2).min(MAX_RTT),
+            None => MAX_RTT,
+        });
+        upstream_health.consecutive_failures += 1;
+
+        if upstream_health.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            upstream_health.unhealthy_until = Some(now + UNHEALTHY_BACKOFF);
+        }
+    }
+
+    fn position(&self, upstream: SocketAddr) -> Option<usize> {
+        self.upstreams.iter().position(|&addr| addr == upstream)
+    }
+}
+
+// Forwarders for specific domains, picked by the longest domain which the name is part of
+pub struct ConditionalForwarders {
+    forwarders: Vec<(String, Arc<Forwarder>)>,
+}
+
+impl ConditionalForwarders {
+    pub fn new() -> Self {
+        ConditionalForwarders {
+            forwarders: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, domain: &str, forwarder: Forwarder) {
+        // Query names are lowercased when they're read, so the domains have to be too
+        let domain = domain.trim_end_matches('.').to_lowercase();
+
+        self.forwarders
+            .retain(|(existing_domain, _)| existing_domain != &domain);
+        self.forwarders.push((domain, Arc::new(forwarder)));
+    }
+
+    // Returns the matching domain along with its forwarder, which is shared so it can outlive a lock
+    pub fn find(&self, qname: &str) -> Option<(&str, &Arc<Forwarder>)> {
+        self.forwarders
+            .iter()
+            .filter(|(domain, _)| is_subdomain(qname, domain))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(domain, forwarder)| (domain.as_str(), forwarder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams() -> Result<Vec<SocketAddr>> {
+        Ok(vec![
+            parse_upstream("10.0.0.1")?,
+            parse_upstream("10.0.0.2:5353")?,
+            parse_upstream("[::1]:53")?,
+        ])
+    }
+
+    #[test]
+    fn order_upstreams_by_strategy() -> Result<()> {
+        /* Arrange */
+        let upstreams = upstreams()?;
+        let ordered = Forwarder::new(upstreams.clone(), UpstreamStrategy::Ordered);
+        let round_robin = Forwarder::new(upstreams.clone(), "round-robin".parse()?);
+        let fastest = Forwarder::new(upstreams.clone(), UpstreamStrategy::Fastest);
+
+        /* Act */
+        ordered.record_success(upstreams[1], Duration::from_millis(5));
+
+        let first_round_robin = round_robin.get_upstreams();
+        let second_round_robin = round_robin.get_upstreams();
+
+        fastest.record_success(upstreams[0], Duration::from_millis(80));
+        fastest.record_success(upstreams[1], Duration::from_millis(20));
+        fastest.record_success(upstreams[2], Duration::from_millis(40));
+
+        /* Assert */
+        assert_eq!(SocketAddr::from(([10, 0, 0, 1], 53)), upstreams[0]);
+        assert_eq!(upstreams, ordered.get_upstreams());
+
+        assert_eq!(upstreams, first_round_robin);
+        assert_eq!(upstreams[1], second_round_robin[0]);
+        assert_eq!(upstreams[0], second_round_robin[2]);
+
+        assert_eq!(
+            vec![upstreams[1], upstreams[2], upstreams[0]],
+            fastest.get_upstreams()
+        );
+
+        assert!("random".parse::<UpstreamStrategy>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_unhealthy_upstreams_last() -> Result<()> {
+        /* Arrange */
+        let upstreams = upstreams()?;
+        let forwarder = Forwarder::new(upstreams.clone(), UpstreamStrategy::Ordered);
+        let now = Instant::now();
+
+        /* Act */
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            forwarder.record_failure_at(upstreams[0], now);
+        }
+        let after_few_failures = forwarder.get_upstreams_at(now);
+
+        forwarder.record_failure_at(upstreams[0], now);
+        let after_many_failures = forwarder.get_upstreams_at(now);
+        let after_backoff = forwarder.get_upstreams_at(now + UNHEALTHY_BACKOFF);
+
+        forwarder.record_failure_at(upstreams[0], now);
+        forwarder.record_success(upstreams[0], Duration::from_millis(10));
+        let after_success = forwarder.get_upstreams_at(now);
+
+        /* Assert */
+        assert_eq!(upstreams, after_few_failures);
+        assert_eq!(
+            vec![upstreams[1], upstreams[2], upstreams[0]],
+            after_many_failures
+        );
+        // Upstreams get another chance once the backoff is over
+        assert_eq!(upstreams, after_backoff);
+        assert_eq!(upstreams, after_success);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_longest_matching_domain() -> Result<()> {
+        /* Arrange */
+        let upstreams = upstreams()?;
+        let mut conditional_forwarders = ConditionalForwarders::new();
+
+        conditional_forwarders.insert(
+            "Example.",
+            Forwarder::new(vec![upstreams[0]], UpstreamStrategy::Ordered),
+        );
+        conditional_forwarders.insert(
+            "corp.example",
+            Forwarder::new(vec![upstreams[1]], UpstreamStrategy::Ordered),
+        );
+
+        /* Act */
+        let corp_match = conditional_forwarders.find("intranet.corp.example");
+        let parent_match = conditional_forwarders.find("notcorp.example");
+        let no_match = conditional_forwarders.find("google.com");
+
+        /* Assert */
+        let (corp_domain, corp_forwarder) = corp_match.unwrap();
+        assert_eq!("corp.example", corp_domain);
+        assert_eq!(vec![upstreams[1]], corp_forwarder.get_upstreams());
+
+        assert_eq!("example", parent_match.unwrap().0);
+        assert!(no_match.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::dns_packet::{Packet, QueryType};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+type LookupKey = (String, QueryType);
+type SharedResult = std::result::Result<Packet, String>;
+
+struct InFlightLookup {
+    result: Mutex<Option<SharedResult>>,
+    done: Condvar,
+}
+
+// Lets identical lookups that run at the same time share a single upstream lookup
+pub struct InFlightLookups {
+    lookups: Mutex<HashMap<LookupKey, Arc<InFlightLookup>>>,
+}
+
+// Publishes the result to the waiting lookups even if the lookup panics
+struct Publisher<'a> {
+    lookups: &'a InFlightLookups,
+    key: LookupKey,
+    lookup: Arc<InFlightLookup>,
+    result: Option<SharedResult>,
+}
+
+impl Drop for Publisher<'_> {
+    fn drop(&mut self) {
+        self.lookups.lookups.lock().unwrap().remove(&self.key);
+
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err("Lookup was aborted".to_string()));
+        *self.lookup.result.lock().unwrap() = Some(result);
+        self.lookup.done.notify_all();
+    }
+}
+
+impl InFlightLookups {
+    pub fn new() -> Self {
+        InFlightLookups {
+            lookups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn run<F>(&self, qname: &str, qtype: QueryType, lookup_fn: F) -> Result<Packet>
+    where
+        F: FnOnce() -> Result<Packet>,
+    {
+        let key = (qname.to_lowercase(), qtype);
+
+        let (lookup, is_leader) = {
+            let mut lookups = self.lookups.lock().unwrap();
+            match lookups.get(&key) {
+                Some(lookup) => (Arc::clone(lookup), false),
+                None => {
+                    let lookup = Arc::new(InFlightLookup {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    lookups.insert(key.clone(), Arc::clone(&lookup));
+                    (lookup, true)
+                }
+            }
+        };
+
+        if !is_leader {
+            let mut result = lookup.result.lock().unwrap();
+            while result.is_none() {
+                result = lookup.done.wait(result).unwrap();
+            }
+
+            return match result.as_ref().unwrap() {
+                Ok(packet) => Ok(packet.clone()),
+                Err(e) => Err(e.clone().into()),
+            };
+        }
+
+        let mut publisher = Publisher {
+            lookups: self,
+            key,
+            lookup,
+            result: None,
+        };
+
+        let result = lookup_fn();
+        publisher.result = Some(match &result {
+            Ok(packet) => Ok(packet.clone()),
+            Err(e) => Err(e.to_string()),
+        });
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn coalesce_identical_lookups() {
+        /* Arrange */
+        let thread_count = 8;
+        let in_flight_lookups = Arc::new(InFlightLookups::new());
+        let lookup_count = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(thread_count));
+
+        /* Act */
+        let threads: Vec<_> = (0..thread_count)
+            .map(|_| {
+                let in_flight_lookups = Arc::clone(&in_flight_lookups);
+                let lookup_count = Arc::clone(&lookup_count);
+                let barrier = Arc::clone(&barrier);
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    in_flight_lookups
+                        .run("google.com", QueryType::A, || {
+                            lookup_count.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(200));
+
+                            let mut packet = Packet::new();
+                            packet.header.id = 123;
+                            Ok(packet)
+                        })
+                        .map(|packet| packet.header.id)
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        /* Assert */
+        assert_eq!(1, lookup_count.load(Ordering::SeqCst));
+        assert!(results.iter().all(|result| result == &Ok(123)));
+        assert!(in_flight_lookups.lookups.lock().unwrap().is_empty());
+    }
+}
@@ -1,9 +1,14 @@
 #![allow(clippy::new_without_default)]
 #![allow(clippy::upper_case_acronyms)]
 
-#[macro_use]
-extern crate num_derive;
-
+pub mod cache;
 pub mod dns_packet;
+pub mod forwarder;
+pub mod in_flight;
 pub mod packet_buffer;
 pub mod resolver;
+pub mod root_hints;
+pub mod secondary;
+pub mod thread_pool;
+pub mod zone;
+pub mod zone_file;
@@ -1,21 +1,61 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-const BUF_SIZE: usize = 512;
+const DEFAULT_BUF_SIZE: usize = 512;
+pub const MAX_BUF_SIZE: usize = 65535;
+pub const CHARACTER_STRING_MAX_LEN: usize = 255;
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+// Returned when writing past the max size, so that callers can tell it apart from invalid data
+#[derive(Debug)]
+pub struct BufferFullError {
+    pos: usize,
+    max_size: usize,
+}
+
+impl fmt::Display for BufferFullError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Position {} of buffer exceeds the max buffer size of {}",
+            self.pos, self.max_size
+        )
+    }
+}
+
+impl Error for BufferFullError {}
+
 pub struct PacketBuffer {
-    buf: [u8; BUF_SIZE],
+    buf: Vec<u8>,
+    max_size: usize,
     pos: usize,
+    name_positions: HashMap<String, usize>,
 }
 
 impl PacketBuffer {
     pub fn new() -> Self {
+        Self::with_max_size(DEFAULT_BUF_SIZE)
+    }
+
+    pub fn with_max_size(max_size: usize) -> Self {
         PacketBuffer {
-            buf: [0; BUF_SIZE],
+            buf: Vec::new(),
+            max_size: max_size.min(MAX_BUF_SIZE),
             pos: 0,
+            name_positions: HashMap::new(),
         }
     }
 
-    pub fn from_u8_array(buf: [u8; BUF_SIZE]) -> Self {
-        PacketBuffer { buf, pos: 0 }
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        PacketBuffer {
+            buf: bytes.to_vec(),
+            max_size: MAX_BUF_SIZE,
+            pos: 0,
+            name_positions: HashMap::new(),
+        }
     }
 
     pub fn pos(&self) -> usize {
@@ -30,11 +70,12 @@ impl PacketBuffer {
         self.pos = pos;
     }
 
-    fn check_end_of_buf(&self, pos: usize) -> Result<()> {
-        if pos >= self.buf.len() {
+    fn check_end_of_data(&self, end_pos: usize) -> Result<()> {
+        if end_pos > self.buf.len() {
             return Err(format!(
-                "Position {} of buffer exceeds the max buffer size of {}",
-                pos, BUF_SIZE
+                "Position {} of buffer exceeds the data size of {}",
+                end_pos,
+                self.buf.len()
             )
             .into());
         }
@@ -42,16 +83,32 @@ impl PacketBuffer {
         Ok(())
     }
 
+    fn reserve_up_to(&mut self, pos: usize) -> Result<()> {
+        if pos >= self.max_size {
+            return Err(BufferFullError {
+                pos,
+                max_size: self.max_size,
+            }
+            .into());
+        }
+
+        if pos >= self.buf.len() {
+            self.buf.resize(pos + 1, 0);
+        }
+
+        Ok(())
+    }
+
     fn get(&self, pos: usize) -> Result<u8> {
-        self.check_end_of_buf(pos)?;
+        self.check_end_of_data(pos + 1)?;
 
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
-        self.check_end_of_buf(start + len)?;
+        self.check_end_of_data(start + len)?;
 
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     fn read_u8(&mut self) -> Result<u8> {
@@ -74,7 +131,7 @@ impl PacketBuffer {
         let mut pos = self.pos();
 
         let mut jumps_performed = 0;
-        let max_jumps = 5; // Guard against cycles
+        let max_jumps = 127; // Guard against cycles, as names have at most 127 labels
 
         loop {
             if jumps_performed > max_jumps {
@@ -108,8 +165,10 @@ impl PacketBuffer {
                     name.push('.');
                 }
 
+                // Dots within labels are escaped, so that they aren't mistaken for separators
                 let str_buffer = self.get_range(pos, label_len as usize)?;
-                name.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                let label = String::from_utf8_lossy(str_buffer).to_lowercase();
+                name.push_str(&label.replace('\\', "\\\\").replace('.', "\\."));
 
                 pos += label_len as usize;
             }
@@ -122,8 +181,22 @@ impl PacketBuffer {
         Ok(name)
     }
 
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let bytes = self.get_range(self.pos, len)?.to_vec();
+        self.step(len);
+
+        Ok(bytes)
+    }
+
+    // Character-strings are arbitrary bytes, which aren't necessarily text
+    pub fn read_character_string(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u8()? as usize;
+
+        self.read_bytes(len)
+    }
+
     pub fn write_u8(&mut self, val: u8) -> Result<()> {
-        self.check_end_of_buf(self.pos)?;
+        self.reserve_up_to(self.pos)?;
 
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -146,7 +219,40 @@ impl PacketBuffer {
     }
 
     pub fn write_compressed_name(&mut self, name: &str) -> Result<()> {
-        for label in name.split('.') {
+        self.write_name_labels(name, true)
+    }
+
+    // Some record types (e.g. SRV) forbid compressing the names in their data
+    pub fn write_uncompressed_name(&mut self, name: &str) -> Result<()> {
+        self.write_name_labels(name, false)
+    }
+
+    fn write_name_labels(&mut self, name: &str, compress: bool) -> Result<()> {
+        // The root domain consists of just the null terminator
+        let labels: Vec<&str> = split_labels(name)
+            .into_iter()
+            .filter(|label| !label.is_empty())
+            .collect();
+
+        for (i, label) in labels.iter().enumerate() {
+            let suffix = labels[i..].join(".").to_lowercase();
+
+            if compress {
+                if let Some(&suffix_pos) = self.name_positions.get(&suffix) {
+                    // Point to the previously written suffix instead of repeating it
+                    let two_msb_mask = 0xC000;
+                    self.write_u16(two_msb_mask | suffix_pos as u16)?;
+
+                    return Ok(());
+                }
+            }
+
+            // Pointers can only address the first part of the packet
+            if self.pos <= MAX_POINTER_OFFSET {
+                self.name_positions.entry(suffix).or_insert(self.pos);
+            }
+
+            let label = unescape(label);
             let len = label.len();
 
             let label_len_limit = 63;
@@ -158,7 +264,7 @@ impl PacketBuffer {
 
             self.write_u8(len as u8)?;
 
-            for &b in label.as_bytes() {
+            for b in label {
                 self.write_u8(b)?;
             }
         }
@@ -168,8 +274,31 @@ impl PacketBuffer {
         Ok(())
     }
 
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for &b in bytes {
+            self.write_u8(b)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_character_string(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = bytes.len();
+
+        if len > CHARACTER_STRING_MAX_LEN {
+            return Err(format!(
+                "Character string exceeds max length of {} bytes",
+                CHARACTER_STRING_MAX_LEN
+            )
+            .into());
+        }
+
+        self.write_u8(len as u8)?;
+        self.write_bytes(bytes)
+    }
+
     fn set_u8(&mut self, pos: usize, val: u8) -> Result<()> {
-        self.check_end_of_buf(pos)?;
+        self.reserve_up_to(pos)?;
 
         self.buf[pos] = val;
 
@@ -184,6 +313,60 @@ impl PacketBuffer {
     }
 }
 
+// Splits a name at the dots between its labels, leaving escaped dots ("\.") in their label
+pub fn split_labels(name: &str) -> Vec<&str> {
+    let mut labels = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in name.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '.' if !escaped => {
+                labels.push(&name[start..i]);
+                start = i + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    labels.push(&name[start..]);
+
+    labels
+}
+
+// Drops the backslashes which escape the character following them, and decodes "\DDD" escapes of
+// bytes into the bytes themselves
+pub fn unescape(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            unescaped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let decimal_byte = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| digits.iter().all(u8::is_ascii_digit))
+            .and_then(|digits| std::str::from_utf8(digits).ok()?.parse::<u8>().ok());
+        match decimal_byte {
+            Some(b) => {
+                unescaped.push(b);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i + 1]);
+                i += 2;
+            }
+        }
+    }
+
+    unescaped
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,4 +389,89 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn write_and_read_name_with_escaped_dot() -> Result<()> {
+        /* Arrange */
+        let mut buffer = PacketBuffer::new();
+        let domain_name = "first\\.last.example.com";
+
+        /* Act */
+        buffer.write_compressed_name(domain_name)?;
+
+        buffer.seek(0);
+        let read_domain_name = buffer.read_compressed_name()?;
+
+        /* Assert */
+        assert_eq!(
+            &[10, b'f', b'i', b'r', b's', b't', b'.'],
+            buffer.get_range(0, 7)?
+        );
+        assert_eq!(domain_name, read_domain_name);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_up_to_max_size() -> Result<()> {
+        /* Arrange */
+        let mut default_buffer = PacketBuffer::new();
+        let mut large_buffer = PacketBuffer::with_max_size(MAX_BUF_SIZE);
+        let bytes = vec![0xAB; 4096];
+
+        /* Act */
+        let default_result = default_buffer.write_bytes(&bytes);
+        let large_result = large_buffer.write_bytes(&bytes);
+
+        /* Assert */
+        assert!(default_result.is_err());
+        assert_eq!(DEFAULT_BUF_SIZE, default_buffer.pos());
+
+        assert!(large_result.is_ok());
+        assert_eq!(bytes.len(), large_buffer.pos());
+
+        let mut read_buffer = PacketBuffer::from_bytes(large_buffer.get_range(0, bytes.len())?);
+        assert_eq!(bytes, read_buffer.read_bytes(bytes.len())?);
+        assert!(read_buffer.read_u8().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_and_read_name_compression() -> Result<()> {
+        /* Arrange */
+        let mut buffer = PacketBuffer::new();
+        let domain_names = [
+            "google.com",
+            "ns1.google.com",
+            "NS2.Google.com",
+            "ns1.google.com",
+        ];
+
+        /* Act */
+        let mut name_positions = Vec::new();
+        for domain_name in domain_names.iter() {
+            name_positions.push(buffer.pos());
+            buffer.write_compressed_name(domain_name)?;
+        }
+        let srv_target_pos = buffer.pos();
+        buffer.write_uncompressed_name("ns1.google.com")?;
+
+        /* Assert */
+        // "ns1" label followed by a pointer to "google.com"
+        assert_eq!(name_positions[1] + 4 + 2, name_positions[2]);
+        // Just a pointer to the previous "ns1.google.com"
+        assert_eq!(name_positions[3] + 2, srv_target_pos);
+        assert_eq!(srv_target_pos + "ns1.google.com".len() + 2, buffer.pos());
+
+        for (pos, domain_name) in name_positions.iter().zip(domain_names.iter()) {
+            buffer.seek(*pos);
+            assert_eq!(domain_name.to_lowercase(), buffer.read_compressed_name()?);
+        }
+
+        buffer.seek(srv_target_pos);
+        assert_eq!("ns1.google.com", buffer.read_compressed_name()?);
+
+        Ok(())
+    }
 }
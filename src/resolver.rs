@@ -1,19 +1,607 @@
-use crate::dns_packet::{BufferIO, Packet, Query, QueryType, ReturnCode};
-use crate::packet_buffer::PacketBuffer;
+use crate::cache::Cache;
+use crate::dns_packet::{
+    is_subdomain, BufferIO, Opcode, Packet, Query, QueryType, ResourceRecord, ReturnCode,
+    EDNS_VERSION,
+};
+use crate::forwarder::{ConditionalForwarders, Forwarder};
+use crate::in_flight::InFlightLookups;
+use crate::packet_buffer::{split_labels, BufferFullError, PacketBuffer, MAX_BUF_SIZE};
+use crate::root_hints::RootHints;
+use crate::secondary::{apply_transfer, is_transfer_complete, SecondaryZone};
+use crate::zone::{get_soa_serial, is_serial_newer, Zone, Zones};
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+// Avoids IP fragmentation on common links, as agreed on for DNS Flag Day 2020
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TCP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+const LOOKUP_PORT_RANGE: std::ops::RangeInclusive<u16> = 49152..=65535;
+const LOOKUP_BIND_ATTEMPTS: usize = 10;
+const DEFAULT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_LOOKUP_ATTEMPTS: usize = 2;
+const DNS_PORT: u16 = 53;
+const MAX_CNAME_CHAIN_LEN: usize = 8;
+const TCP_LISTEN_BACKLOG: i32 = 128;
+const LISTEN_BIND_ATTEMPTS: usize = 10;
+// Zone transfers are split into messages of about this size, well below the 64KiB maximum
+const TRANSFER_MESSAGE_SIZE: usize = 16 * 1024;
+
+// IPv4 clients of dual-stack sockets show up with IPv4-mapped IPv6 addresses
+fn unmap_ip_addr(ip_addr: IpAddr) -> IpAddr {
+    match ip_addr {
+        IpAddr::V6(ipv6_addr) => match ipv6_addr.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip_addr,
+        },
+        IpAddr::V4(_) => ip_addr,
+    }
+}
+
+// Sockets have to be of the same protocol as the address they send to
+fn unspecified_ip_addr(ip_addr: IpAddr) -> IpAddr {
+    match ip_addr {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+// Anything else comes from parsing the received data
+fn is_io_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some()
+}
+
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<std::io::Error>() {
+        // Which of the two is returned depends on the platform
+        Some(io_err) => matches!(io_err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        None => false,
+    }
+}
+
+// Retransmits the NOTIFY until the secondary acknowledges it (RFC 1996 section 3.6)
+fn send_notify(
+    notify_packet: &Packet,
+    secondary: SocketAddr,
+    timeout: Duration,
+    attempts: usize,
+) -> Result<()> {
+    let socket = UdpSocket::bind((unspecified_ip_addr(secondary.ip()), 0))?;
+
+    let mut buf = PacketBuffer::new();
+    notify_packet.clone().write_to_buffer(&mut buf)?;
+
+    for _ in 0..attempts {
+        socket.send_to(buf.get_range(0, buf.pos())?, secondary)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let mut raw_buf = [0; UDP_PAYLOAD_SIZE as usize];
+            let (len, src_socket) = match socket.recv_from(&mut raw_buf) {
+                Ok(received) => received,
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut res_buf = PacketBuffer::from_bytes(&raw_buf[..len]);
+            let res_packet = match Packet::from_buffer(&mut res_buf) {
+                Ok(res_packet) => res_packet,
+                Err(e) => {
+                    eprintln!("Ignoring malformed response from {}: {}", src_socket, e);
+                    continue;
+                }
+            };
+
+            if src_socket == secondary && res_packet.is_response_to(notify_packet) {
+                return match res_packet.header.return_code {
+                    ReturnCode::NOERROR => Ok(()),
+                    return_code => Err(format!(
+                        "Secondary {} responded with {:?}",
+                        secondary, return_code
+                    )
+                    .into()),
+                };
+            }
+        }
+    }
+
+    Err(format!(
+        "Timed out waiting for secondary {} to acknowledge",
+        secondary
+    )
+    .into())
+}
+
+// Caps every read and write at the time left, so that a peer trickling bytes can't keep the
+// connection open for longer than that
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    fn time_left(&self) -> io::Result<Duration> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Connection time limit reached",
+            ));
+        }
+
+        Ok(remaining)
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.time_left()?.min(TCP_IDLE_TIMEOUT);
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = self.time_left()?.min(TCP_IDLE_TIMEOUT);
+        self.stream.set_write_timeout(Some(timeout))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
 pub struct Resolver {
     socket: UdpSocket,
+    tcp_listener: TcpListener,
+    cache: Mutex<Cache>,
+    root_hints: RwLock<RootHints>,
+    lookup_timeout: Duration,
+    tcp_connection_timeout: Duration,
+    lookup_attempts: usize,
+    nameserver_port: u16,
+    in_flight_lookups: InFlightLookups,
+    forwarder: RwLock<Option<Arc<Forwarder>>>,
+    conditional_forwarders: RwLock<ConditionalForwarders>,
+    zones: RwLock<Zones>,
+    transfer_allow_list: RwLock<Vec<IpAddr>>,
+    secondary_zones: Mutex<Vec<SecondaryZone>>,
+    secondary_zones_changed: Condvar,
+    notify_list: RwLock<Vec<SocketAddr>>,
 }
 
 impl Resolver {
     pub fn new(bind_addr: &str, port: u16) -> Result<Self> {
-        let socket = UdpSocket::bind((bind_addr, port))?;
+        let addr = (bind_addr, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("Invalid bind address: {}", bind_addr))?;
+        let (socket, tcp_listener) = Self::bind_listeners(addr)?;
+
+        Ok(Resolver {
+            socket,
+            tcp_listener,
+            cache: Mutex::new(Cache::new()),
+            root_hints: RwLock::new(RootHints::new()),
+            lookup_timeout: DEFAULT_LOOKUP_TIMEOUT,
+            tcp_connection_timeout: DEFAULT_TCP_CONNECTION_TIMEOUT,
+            lookup_attempts: DEFAULT_LOOKUP_ATTEMPTS,
+            nameserver_port: DNS_PORT,
+            in_flight_lookups: InFlightLookups::new(),
+            forwarder: RwLock::new(None),
+            conditional_forwarders: RwLock::new(ConditionalForwarders::new()),
+            zones: RwLock::new(Zones::new()),
+            transfer_allow_list: RwLock::new(Vec::new()),
+            secondary_zones: Mutex::new(Vec::new()),
+            secondary_zones_changed: Condvar::new(),
+            notify_list: RwLock::new(Vec::new()),
+        })
+    }
+
+    // With port 0 the UDP socket picks a free port, which may already be taken for TCP
+    fn bind_listeners(addr: SocketAddr) -> Result<(UdpSocket, TcpListener)> {
+        let attempts = if addr.port() == 0 {
+            LISTEN_BIND_ATTEMPTS
+        } else {
+            1
+        };
+
+        let mut last_err = None;
+        for _ in 0..attempts {
+            let socket = Self::bind_udp_socket(addr)?;
+            let tcp_addr = SocketAddr::new(addr.ip(), socket.local_addr()?.port());
+            match Self::bind_tcp_listener(tcp_addr) {
+                Ok(tcp_listener) => return Ok((socket, tcp_listener)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn new_socket(addr: SocketAddr, socket_type: Type, protocol: Protocol) -> Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), socket_type, Some(protocol))?;
+
+        // Listening on "::" accepts IPv4 clients too, whatever the platform's default is
+        if addr.is_ipv6() {
+            socket.set_only_v6(false)?;
+        }
+
+        Ok(socket)
+    }
+
+    fn bind_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
+        let socket = Self::new_socket(addr, Type::DGRAM, Protocol::UDP)?;
+        socket.bind(&addr.into())?;
+
+        Ok(socket.into())
+    }
+
+    fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener> {
+        let socket = Self::new_socket(addr, Type::STREAM, Protocol::TCP)?;
+
+        // Matches TcpListener::bind, which allows restarting while old connections linger
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+
+        socket.bind(&addr.into())?;
+        socket.listen(TCP_LISTEN_BACKLOG)?;
+
+        Ok(socket.into())
+    }
+
+    // How long a client may keep a TCP connection open, however busy it keeps it
+    pub fn set_tcp_connection_timeout(&mut self, timeout: Duration) {
+        self.tcp_connection_timeout = timeout;
+    }
+
+    pub fn set_lookup_timeout(&mut self, timeout: Duration) {
+        self.lookup_timeout = timeout;
+    }
+
+    // How many times each nameserver is tried before the lookup fails
+    pub fn set_lookup_attempts(&mut self, attempts: usize) {
+        self.lookup_attempts = attempts.max(1);
+    }
+
+    // The port root and delegated nameservers are queried on, for nameservers not listening on 53
+    pub fn set_nameserver_port(&mut self, port: u16) {
+        self.nameserver_port = port;
+    }
+
+    pub fn set_root_hints(&self, root_hints: RootHints) {
+        *self.root_hints.write().unwrap() = root_hints;
+    }
+
+    // Forwards all lookups to the given upstream resolvers instead of resolving them iteratively
+    pub fn set_forwarder(&self, forwarder: Forwarder) {
+        *self.forwarder.write().unwrap() = Some(Arc::new(forwarder));
+    }
+
+    // Forwards lookups of names in the domain to the given upstream resolvers
+    pub fn add_conditional_forwarder(&self, domain: &str, forwarder: Forwarder) {
+        self.conditional_forwarders
+            .write()
+            .unwrap()
+            .insert(domain, forwarder);
+    }
+
+    // Serves the zone authoritatively, replacing any zone with the same origin
+    pub fn add_zone(&self, zone: Zone) {
+        let origin = zone.get_origin().to_string();
+        let soa = zone.get_soa().clone();
+
+        let serial = zone.get_serial();
+
+        let previous_serial = self.zones.write().unwrap().insert(zone);
+        match previous_serial {
+            // Secondaries only transfer the zone once its serial has gone up
+            Some(previous_serial) if !is_serial_newer(serial, previous_serial) => {
+                println!(
+                    "Not notifying secondaries of zone {}, as its serial {} isn't newer",
+                    origin, serial
+                );
+            }
+            _ => self.notify_secondaries(&origin, soa),
+        }
+    }
+
+    // Sends a NOTIFY to the address whenever a served zone changes, so that the secondary
+    // doesn't have to wait for its refresh timer
+    pub fn notify_zone_changes_to(&self, secondary: SocketAddr) {
+        self.notify_list.write().unwrap().push(secondary);
+    }
+
+    fn notify_secondaries(&self, origin: &str, soa: ResourceRecord) {
+        let secondaries = self.notify_list.read().unwrap().clone();
+        if secondaries.is_empty() {
+            return;
+        }
+
+        let mut notify_packet = Packet::new();
+        notify_packet.header.id = rand::random();
+        notify_packet.header.opcode = Opcode::NOTIFY;
+        notify_packet.header.authoritative_answer = true;
+        notify_packet
+            .queries
+            .push(Query::new(origin.to_string(), QueryType::SOA));
+        notify_packet.answer_records.push(soa);
+
+        // Unresponsive secondaries mustn't hold up whoever changed the zone
+        let (timeout, attempts) = (self.lookup_timeout, self.lookup_attempts);
+        let origin = origin.to_string();
+        thread::spawn(move || {
+            for secondary in secondaries {
+                match send_notify(&notify_packet, secondary, timeout, attempts) {
+                    Ok(_) => println!("Notified {} of changes to zone {}", secondary, origin),
+                    Err(e) => eprintln!(
+                        "Failed to notify {} of changes to zone {}: {}",
+                        secondary, origin, e
+                    ),
+                }
+            }
+        });
+    }
+
+    // Lets the address transfer any of the served zones, e.g. to keep a secondary server in sync
+    pub fn allow_zone_transfers_to(&self, ip_addr: IpAddr) {
+        self.transfer_allow_list
+            .write()
+            .unwrap()
+            .push(unmap_ip_addr(ip_addr));
+    }
+
+    // Serves the zone as a copy of the one on the primary, once it's been transferred from there
+    pub fn add_secondary_zone(&self, origin: &str, primary: SocketAddr) {
+        let secondary_zone = SecondaryZone::new(origin, primary);
+
+        let mut secondary_zones = self.secondary_zones.lock().unwrap();
+        secondary_zones.retain(|existing| existing.get_origin() != secondary_zone.get_origin());
+        secondary_zones.push(secondary_zone);
+    }
+
+    // Refreshes the secondary zones which are due, returning when the next one will be
+    pub fn refresh_secondary_zones(&self) -> Option<Instant> {
+        // The zones aren't locked during transfers, so that NOTIFY messages can still be handled
+        let due_zones: Vec<SecondaryZone> = self
+            .secondary_zones
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|zone| zone.is_due())
+            .map(|zone| {
+                zone.start_refresh();
+                zone.clone()
+            })
+            .collect();
+
+        for due_zone in due_zones {
+            let origin = due_zone.get_origin();
+            let result = self.refresh_secondary_zone(&due_zone);
+
+            let mut secondary_zones = self.secondary_zones.lock().unwrap();
+            let secondary_zone = match secondary_zones
+                .iter_mut()
+                .find(|zone| zone.get_origin() == origin)
+            {
+                Some(secondary_zone) => secondary_zone,
+                None => continue,
+            };
+
+            match result {
+                Ok(soa) => secondary_zone.record_refresh(&soa),
+                Err(e) => {
+                    eprintln!("Failed to refresh zone {}: {}", origin, e);
+
+                    // Stale data mustn't be served as authoritative forever
+                    if secondary_zone.record_failure()
+                        && self.zones.write().unwrap().remove(origin).is_some()
+                    {
+                        eprintln!("Zone {} expired, no longer serving it", origin);
+                    }
+                }
+            }
+        }
+
+        self.secondary_zones
+            .lock()
+            .unwrap()
+            .iter()
+            .map(SecondaryZone::get_next_refresh_at)
+            .min()
+    }
+
+    // Sleeps until the next refresh is due, or until a NOTIFY makes one due sooner
+    pub fn wait_for_secondary_refresh(&self, next_refresh_at: Instant) {
+        let secondary_zones = self.secondary_zones.lock().unwrap();
+        let timeout = next_refresh_at.saturating_duration_since(Instant::now());
+
+        let _ = self
+            .secondary_zones_changed
+            .wait_timeout_while(secondary_zones, timeout, |secondary_zones| {
+                !secondary_zones.iter().any(SecondaryZone::is_due)
+            })
+            .unwrap();
+    }
+
+    // Transfers the zone if the primary has a newer version of it, returning the current SOA
+    fn refresh_secondary_zone(&self, secondary_zone: &SecondaryZone) -> Result<ResourceRecord> {
+        let origin = secondary_zone.get_origin();
+        let primary = secondary_zone.get_primary();
+
+        let current_records = {
+            let zones = self.zones.read().unwrap();
+            zones
+                .find(origin)
+                .filter(|zone| zone.get_origin() == origin)
+                .map(Zone::get_all_records)
+        };
+
+        if let Some(current_records) = &current_records {
+            let current_soa = &current_records[0];
+            let response = self.lookup(origin, QueryType::SOA, (primary.ip(), primary.port()))?;
+            let primary_serial = response
+                .answer_records
+                .iter()
+                .find_map(get_soa_serial)
+                .ok_or_else(|| format!("Primary {} didn't return the zone's SOA", primary))?;
+
+            if !is_serial_newer(primary_serial, get_soa_serial(current_soa).unwrap()) {
+                return Ok(current_soa.clone());
+            }
+        }
+
+        // Only the changes are transferred if the primary still knows them
+        let incremental_records = current_records.as_ref().and_then(|current_records| {
+            let transferred_records = self
+                .request_transfer(origin, QueryType::IXFR, primary, Some(&current_records[0]))
+                .and_then(|transfer_records| {
+                    apply_transfer(current_records, &transfer_records, QueryType::IXFR)
+                });
+
+            match transferred_records {
+                Ok(records) => Some(records),
+                Err(e) => {
+                    eprintln!("Incremental transfer of zone {} failed: {}", origin, e);
+                    None
+                }
+            }
+        });
 
-        Ok(Resolver { socket })
+        let records = match incremental_records {
+            Some(records) => records,
+            None => {
+                let transfer_records =
+                    self.request_transfer(origin, QueryType::AXFR, primary, None)?;
+                apply_transfer(&[], &transfer_records, QueryType::AXFR)?
+            }
+        };
+
+        let zone = Zone::from_records(origin, records)?;
+        let soa = zone.get_soa().clone();
+        println!(
+            "Transferred zone {} with serial {} from {}",
+            origin,
+            zone.get_serial(),
+            primary
+        );
+        self.add_zone(zone);
+
+        Ok(soa)
+    }
+
+    // Collects the records of all messages of an AXFR or IXFR response
+    fn request_transfer(
+        &self,
+        origin: &str,
+        qtype: QueryType,
+        primary: SocketAddr,
+        current_soa: Option<&ResourceRecord>,
+    ) -> Result<Vec<ResourceRecord>> {
+        let mut stream = TcpStream::connect_timeout(&primary, self.lookup_timeout)?;
+        stream.set_read_timeout(Some(self.lookup_timeout))?;
+        stream.set_write_timeout(Some(self.lookup_timeout))?;
+
+        let mut req_packet = Packet::new();
+        req_packet.header.id = rand::random();
+        req_packet
+            .queries
+            .push(Query::new(origin.to_string(), qtype));
+
+        // IXFR queries carry the secondary's SOA, so that the primary knows which changes to send
+        if let Some(current_soa) = current_soa {
+            req_packet.authoritative_records.push(current_soa.clone());
+        }
+        self.send_tcp_packet(req_packet.clone(), &mut stream)?;
+
+        let known_serial = current_soa.and_then(get_soa_serial);
+        let mut transfer_records = Vec::new();
+
+        while !is_transfer_complete(&transfer_records, known_serial) {
+            let res_packet = self.receive_tcp_packet(&mut stream)?;
+
+            // Only the first message has to repeat the question
+            if !res_packet.header.response || res_packet.header.id != req_packet.header.id {
+                return Err(format!("Unexpected zone transfer message from {}", primary).into());
+            }
+            if res_packet.header.return_code != ReturnCode::NOERROR {
+                return Err(format!(
+                    "Primary {} responded with {:?}",
+                    primary, res_packet.header.return_code
+                )
+                .into());
+            }
+            if res_packet.answer_records.is_empty() {
+                return Err(format!("Zone transfer from {} ended early", primary).into());
+            }
+
+            transfer_records.extend(res_packet.answer_records);
+        }
+
+        Ok(transfer_records)
+    }
+
+    // Replaces the root hints with the current root nameservers, as reported by the roots
+    pub fn prime_root_hints(&self) -> Result<()> {
+        let root_domain = "";
+        let root_servers = self.with_nameserver_port(&self.get_root_ip_addrs());
+        let response = self.lookup_any(root_domain, QueryType::NS, &root_servers)?;
+
+        let records: Vec<ResourceRecord> = response
+            .answer_records
+            .iter()
+            .chain(response.additional_records.iter())
+            .cloned()
+            .collect();
+
+        let root_hints = RootHints::from_records(&records);
+        if root_hints.get_ip_addrs().is_empty() {
+            return Err("Priming response doesn't contain any root nameserver addresses".into());
+        }
+
+        println!(
+            "Primed root hints with {} nameservers",
+            root_hints.get_servers().len()
+        );
+        self.set_root_hints(root_hints);
+        self.cache.lock().unwrap().insert(&records);
+
+        Ok(())
+    }
+
+    fn with_nameserver_port(&self, ip_addrs: &[IpAddr]) -> Vec<(IpAddr, u16)> {
+        ip_addrs
+            .iter()
+            .map(|&ip_addr| (ip_addr, self.nameserver_port))
+            .collect()
+    }
+
+    fn get_root_ip_addrs(&self) -> Vec<IpAddr> {
+        self.root_hints.read().unwrap().get_ip_addrs()
     }
 
     fn send_packet(
@@ -21,178 +609,2138 @@ impl Resolver {
         mut packet: Packet,
         socket: &UdpSocket,
         dst_socket: &(IpAddr, u16),
+        max_size: u16,
     ) -> Result<()> {
-        let mut buf = PacketBuffer::new();
-        packet.write_to_buffer(&mut buf)?;
+        // Drop records until the packet fits, so the client can at least retry over TCP
+        let buf = loop {
+            let mut buf = PacketBuffer::with_max_size(max_size as usize);
+            match packet.write_to_buffer(&mut buf) {
+                Ok(_) => break buf,
+                Err(e) if e.is::<BufferFullError>() && packet.drop_record() => {}
+                Err(e) => return Err(e),
+            }
+        };
+
         socket.send_to(buf.get_range(0, buf.pos())?, dst_socket)?;
 
         Ok(())
     }
 
     fn receive_packet(&self, socket: &UdpSocket) -> Result<(Packet, SocketAddr)> {
-        let mut raw_buf: [u8; 512] = [0; 512];
-        let (_, src_socket) = socket.recv_from(&mut raw_buf)?;
-        let mut buf = PacketBuffer::from_u8_array(raw_buf);
+        let mut raw_buf = [0; UDP_PAYLOAD_SIZE as usize];
+        let (len, src_socket) = socket.recv_from(&mut raw_buf)?;
+        let mut buf = PacketBuffer::from_bytes(&raw_buf[..len]);
         let packet = Packet::from_buffer(&mut buf)?;
 
         Ok((packet, src_socket))
     }
 
-    pub fn handle_query(&self) -> Result<()> {
-        let (req_packet, src_socket) = self.receive_packet(&self.socket)?;
+    fn send_tcp_packet<S: Write>(&self, mut packet: Packet, stream: &mut S) -> Result<()> {
+        let mut buf = PacketBuffer::with_max_size(MAX_BUF_SIZE);
+        packet.write_to_buffer(&mut buf)?;
 
-        let mut res_packet = Packet::new();
-        res_packet.header.id = req_packet.header.id;
-        res_packet.header.recursion_desired = true;
-        res_packet.header.recursion_available = true;
-        res_packet.header.response = true;
+        // Messages over TCP are prefixed with their length
+        let len = buf.pos() as u16;
+        stream.write_all(&len.to_be_bytes())?;
+        stream.write_all(buf.get_range(0, buf.pos())?)?;
 
-        if req_packet.queries.is_empty() {
-            res_packet.header.return_code = ReturnCode::FORMERR;
-        }
+        Ok(())
+    }
 
-        for query in req_packet.queries.iter() {
-            println!("Received query: {:?}", query);
+    fn receive_tcp_packet<S: Read>(&self, stream: &mut S) -> Result<Packet> {
+        let mut len_buf = [0; 2];
+        stream.read_exact(&mut len_buf)?;
 
-            if let Ok(result) = self.recursive_lookup(&query.qname, query.qtype) {
-                res_packet.queries.push(query.clone());
-                res_packet.header.return_code = result.header.return_code;
+        let mut raw_buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut raw_buf)?;
+        let mut buf = PacketBuffer::from_bytes(&raw_buf);
 
-                for rec in result.answer_records {
-                    println!("Answer record: {:?}", rec);
-                    res_packet.answer_records.push(rec);
-                }
-                for rec in result.authoritative_records {
-                    println!("Authority record: {:?}", rec);
-                    res_packet.authoritative_records.push(rec);
-                }
-                for rec in result.additional_records {
-                    println!("Additional record: {:?}", rec);
-                    res_packet.additional_records.push(rec);
-                }
-            } else {
-                res_packet.header.return_code = ReturnCode::SERVFAIL;
-            }
-        }
+        Packet::from_buffer(&mut buf)
+    }
+
+    pub fn handle_query(&self) -> Result<()> {
+        let (req_packet, src_socket) = self.receive_query()?;
+
+        self.respond_to_query(&req_packet, src_socket)
+    }
+
+    // Receiving and responding are separate so that responses can be built on other threads
+    pub fn receive_query(&self) -> Result<(Packet, SocketAddr)> {
+        self.receive_packet(&self.socket)
+    }
+
+    pub fn respond_to_query(&self, req_packet: &Packet, src_socket: SocketAddr) -> Result<()> {
+        let max_res_size = req_packet.get_max_udp_payload_size().min(UDP_PAYLOAD_SIZE);
+        let res_packet = self.build_response(req_packet, src_socket.ip());
 
         self.send_packet(
             res_packet,
             &self.socket,
             &(src_socket.ip(), src_socket.port()),
+            max_res_size,
         )?;
 
         Ok(())
     }
 
-    fn lookup(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet> {
-        let lookup_socket = UdpSocket::bind(("0.0.0.0", 43210))?;
-
-        let mut req_packet = Packet::new();
-        req_packet.header.id = 1234;
-        req_packet.header.queries_total = 1;
-        req_packet.header.recursion_desired = true;
-        req_packet
-            .queries
-            .push(Query::new(qname.to_string(), qtype));
-
-        self.send_packet(req_packet, &lookup_socket, &server)?;
+    pub fn accept_tcp_connection(&self) -> Result<TcpStream> {
+        let (stream, _) = self.tcp_listener.accept()?;
 
-        let (res_packet, _) = self.receive_packet(&lookup_socket)?;
-        Ok(res_packet)
+        Ok(stream)
     }
 
-    fn recursive_lookup(&self, qname: &str, qtype: QueryType) -> Result<Packet> {
-        let a_root_servers_net_ip = "198.41.0.4";
-        let mut ns = IpAddr::V4(a_root_servers_net_ip.parse::<Ipv4Addr>().unwrap());
+    pub fn handle_tcp_connection(&self, stream: TcpStream) -> Result<()> {
+        let peer_ip_addr = stream.peer_addr()?.ip();
+        let mut stream = DeadlineStream {
+            stream,
+            deadline: Instant::now() + self.tcp_connection_timeout,
+        };
 
+        // Clients may send several queries over the same connection
         loop {
-            println!("Performing lookup of {:?} {} with ns {}", qtype, qname, ns);
-
-            let server = (ns, 53);
-            let response = self.lookup(qname, qtype, server)?;
-
-            if (!response.answer_records.is_empty()
-                && response.header.return_code == ReturnCode::NOERROR)
-                || response.header.return_code == ReturnCode::NXDOMAIN
-            {
-                return Ok(response);
-            }
+            let req_packet = match self.receive_tcp_packet(&mut stream) {
+                Ok(packet) => packet,
+                Err(e) => match e.downcast_ref::<std::io::Error>() {
+                    Some(io_err) if io_err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    _ => return Err(e),
+                },
+            };
 
-            if let Some(&new_ns) = response.get_ns_from_additional_records(qname).last() {
-                ns = IpAddr::V4(*new_ns);
+            let is_zone_transfer = req_packet.header.opcode == Opcode::QUERY
+                && req_packet.queries.len() == 1
+                && matches!(
+                    req_packet.queries[0].qtype,
+                    QueryType::AXFR | QueryType::IXFR
+                );
+            if is_zone_transfer {
+                self.transfer_zone(&req_packet, peer_ip_addr, &mut stream)?;
                 continue;
             }
 
-            let new_ns_host = match response.get_ns_hosts(qname).last() {
-                Some(&host) => host,
-                None => return Ok(response),
-            };
+            let res_packet = self.build_response(&req_packet, peer_ip_addr);
+            self.send_tcp_packet(res_packet, &mut stream)?;
+        }
+    }
 
-            let recursive_response = self.recursive_lookup(new_ns_host, qtype)?;
+    fn is_transfer_allowed(&self, ip_addr: IpAddr) -> bool {
+        self.transfer_allow_list
+            .read()
+            .unwrap()
+            .contains(&unmap_ip_addr(ip_addr))
+    }
 
-            ns = match recursive_response.get_answer_a_records().last() {
-                Some(&new_ns) => IpAddr::V4(*new_ns),
-                None => return Ok(response),
-            };
+    // The current SOA of the zone, if it's served here and the peer may request an IXFR of it
+    fn find_transfer_soa(&self, query: &Query, peer_ip_addr: IpAddr) -> Option<ResourceRecord> {
+        if query.qtype != QueryType::IXFR || !self.is_transfer_allowed(peer_ip_addr) {
+            return None;
         }
+
+        let zones = self.zones.read().unwrap();
+        zones
+            .find(&query.qname)
+            .filter(|zone| zone.get_origin() == query.qname)
+            .map(|zone| zone.get_soa().clone())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn new_transfer_packet(req_packet: &Packet) -> Packet {
+        let mut res_packet = Packet::new();
+        res_packet.header.id = req_packet.header.id;
+        res_packet.header.response = true;
+        res_packet.header.authoritative_answer = true;
 
-    #[test]
-    fn recursive_lookup() -> Result<()> {
-        /* Arrange */
-        let localhost_str = "127.0.0.1";
-        let localhost_addr = localhost_str.parse::<Ipv4Addr>()?;
+        res_packet
+    }
 
-        // Resolver
-        let resolver_port = 2053;
-        let resolver = Resolver::new(localhost_str, resolver_port)?;
+    // Sends the whole zone or the changes to it, spread across as many messages as needed
+    // (RFC 5936 and RFC 1995)
+    fn transfer_zone<S: Write>(
+        &self,
+        req_packet: &Packet,
+        peer_ip_addr: IpAddr,
+        stream: &mut S,
+    ) -> Result<()> {
+        let query = &req_packet.queries[0];
 
-        // Client
-        let socket = UdpSocket::bind((localhost_str, 2054))?;
+        let known_serial = match query.qtype {
+            QueryType::IXFR => req_packet
+                .authoritative_records
+                .iter()
+                .find_map(get_soa_serial),
+            _ => None,
+        };
 
-        // Query Packet
-        let qname = "google.com";
-        let qtype = QueryType::A;
+        // The zone is copied so that it's not locked while the transfer is in progress
+        let transfer_records = {
+            let zones = self.zones.read().unwrap();
+            match zones.find(&query.qname) {
+                Some(zone) if zone.get_origin() == query.qname => Some(
+                    // The whole zone is sent when the changes since the secondary's version are gone
+                    known_serial
+                        .and_then(|serial| zone.get_incremental_transfer_records(serial))
+                        .unwrap_or_else(|| zone.get_transfer_records()),
+                ),
+                _ => None,
+            }
+        };
 
-        let mut packet = Packet::new();
-        packet.header.id = 123;
-        packet.header.queries_total = 1;
-        packet.header.recursion_desired = true;
-        packet.queries.push(Query::new(qname.to_string(), qtype));
+        let mut res_packet = Self::new_transfer_packet(req_packet);
+        res_packet.queries.push(query.clone());
 
-        let mut req_buffer = PacketBuffer::new();
-        packet.write_to_buffer(&mut req_buffer)?;
+        let transfer_records = match transfer_records {
+            Some(records) if self.is_transfer_allowed(peer_ip_addr) => records,
+            _ => {
+                eprintln!(
+                    "Refused transfer of zone {} to {}",
+                    query.qname, peer_ip_addr
+                );
+                res_packet.header.authoritative_answer = false;
+                res_packet.header.return_code = ReturnCode::REFUSED;
+                return self.send_tcp_packet(res_packet, stream);
+            }
+        };
 
-        /* Act */
-        socket.send_to(
-            req_buffer.get_range(0, req_buffer.pos())?,
-            (localhost_addr, resolver_port),
-        )?;
+        println!("Transferring zone {} to {}", query.qname, peer_ip_addr);
 
-        resolver.handle_query()?;
+        let mut message_size = 0;
+        for mut record in transfer_records {
+            let mut record_buf = PacketBuffer::with_max_size(MAX_BUF_SIZE);
+            record.write_to_buffer(&mut record_buf)?;
 
-        let mut raw_buf: [u8; 512] = [0; 512];
-        socket.recv_from(&mut raw_buf)?;
-        let mut res_buf = PacketBuffer::from_u8_array(raw_buf);
-        let res_packet = Packet::from_buffer(&mut res_buf)?;
+            // Sizes are measured without name compression, so the messages can only end up smaller
+            if message_size + record_buf.pos() > TRANSFER_MESSAGE_SIZE
+                && !res_packet.answer_records.is_empty()
+            {
+                let full_packet =
+                    std::mem::replace(&mut res_packet, Self::new_transfer_packet(req_packet));
+                self.send_tcp_packet(full_packet, stream)?;
+                message_size = 0;
+            }
 
-        /* Assert */
-        assert!(res_packet.queries.len() > 0);
+            message_size += record_buf.pos();
+            res_packet.answer_records.push(record);
+        }
+
+        self.send_tcp_packet(res_packet, stream)
+    }
+
+    fn build_response(&self, req_packet: &Packet, src_ip_addr: IpAddr) -> Packet {
+        // NOTIFY messages come from primaries rather than clients
+        if req_packet.header.opcode == Opcode::NOTIFY {
+            return self.build_notify_response(req_packet, src_ip_addr);
+        }
+
+        let mut res_packet = Packet::new();
+        res_packet.header.id = req_packet.header.id;
+        res_packet.header.opcode = req_packet.header.opcode;
+        res_packet.header.recursion_desired = true;
+        res_packet.header.recursion_available = true;
+        res_packet.header.response = true;
+
+        if req_packet.header.opcode != Opcode::QUERY {
+            res_packet.header.return_code = ReturnCode::NOTIMP;
+            return res_packet;
+        }
+
+        if req_packet.queries.is_empty() {
+            res_packet.header.return_code = ReturnCode::FORMERR;
+        }
+
+        // Only reply with EDNS if the client used it
+        if let Some(&ResourceRecord::OPT { version, .. }) = req_packet.get_opt_record() {
+            let unsupported_version = version > EDNS_VERSION;
+
+            res_packet
+                .additional_records
+                .push(ResourceRecord::new_opt(UDP_PAYLOAD_SIZE));
+
+            if unsupported_version {
+                res_packet.header.return_code = ReturnCode::BADVERS;
+                return res_packet;
+            }
+        }
+
+        for query in req_packet.queries.iter() {
+            println!("Received query: {:?}", query);
+
+            // Zone transfers are only possible over TCP, but an IXFR over UDP is answered with the
+            // current SOA, which tells the secondary whether to transfer over TCP (RFC 1995 section 2)
+            if matches!(query.qtype, QueryType::AXFR | QueryType::IXFR) {
+                res_packet.queries.push(query.clone());
+
+                match self.find_transfer_soa(query, src_ip_addr) {
+                    Some(soa) => {
+                        res_packet.header.authoritative_answer = true;
+                        res_packet.answer_records.push(soa);
+                    }
+                    None => res_packet.header.return_code = ReturnCode::REFUSED,
+                }
+                continue;
+            }
+
+            let lookup_result = match self.lookup_zone(&query.qname, query.qtype) {
+                Some(zone_response) => {
+                    res_packet.header.authoritative_answer =
+                        zone_response.header.authoritative_answer;
+                    Ok(zone_response)
+                }
+                None => self.in_flight_lookups.run(&query.qname, query.qtype, || {
+                    self.resolve(&query.qname, query.qtype)
+                }),
+            };
+
+            if let Ok(result) = lookup_result {
+                res_packet.queries.push(query.clone());
+                res_packet.header.return_code = result.header.return_code;
+
+                for rec in result.answer_records {
+                    println!("Answer record: {:?}", rec);
+                    res_packet.answer_records.push(rec);
+                }
+                for rec in result.authoritative_records {
+                    println!("Authority record: {:?}", rec);
+                    res_packet.authoritative_records.push(rec);
+                }
+                for rec in result.additional_records {
+                    // The upstream OPT record only applies to the upstream hop
+                    if let ResourceRecord::OPT { .. } = rec {
+                        continue;
+                    }
+
+                    println!("Additional record: {:?}", rec);
+                    res_packet.additional_records.push(rec);
+                }
+            } else {
+                res_packet.header.return_code = ReturnCode::SERVFAIL;
+            }
+        }
+
+        res_packet
+    }
+
+    // Acknowledges a primary's NOTIFY and makes the zone due for a refresh (RFC 1996 section 4.7)
+    fn build_notify_response(&self, req_packet: &Packet, src_ip_addr: IpAddr) -> Packet {
+        let mut res_packet = Packet::new();
+        res_packet.header.id = req_packet.header.id;
+        res_packet.header.opcode = Opcode::NOTIFY;
+        res_packet.header.authoritative_answer = true;
+        res_packet.header.response = true;
+        res_packet.queries = req_packet.queries.clone();
+
+        let query = match req_packet.queries.as_slice() {
+            [query] if query.qtype == QueryType::SOA => query,
+            _ => {
+                res_packet.header.return_code = ReturnCode::FORMERR;
+                return res_packet;
+            }
+        };
+
+        // Only the primary is trusted with saying that the zone changed
+        let mut secondary_zones = self.secondary_zones.lock().unwrap();
+        let secondary_zone = secondary_zones.iter_mut().find(|zone| {
+            zone.get_origin() == query.qname
+                && unmap_ip_addr(zone.get_primary().ip()) == unmap_ip_addr(src_ip_addr)
+        });
+
+        match secondary_zone {
+            Some(secondary_zone) => {
+                println!(
+                    "Received NOTIFY for zone {} from {}",
+                    query.qname, src_ip_addr
+                );
+                secondary_zone.request_refresh();
+                self.secondary_zones_changed.notify_all();
+            }
+            None => {
+                eprintln!(
+                    "Refused NOTIFY for zone {} from {}",
+                    query.qname, src_ip_addr
+                );
+                res_packet.header.authoritative_answer = false;
+                res_packet.header.return_code = ReturnCode::REFUSED;
+            }
+        }
+
+        res_packet
+    }
+
+    // Names in the served zones are answered from them instead of being resolved
+    fn lookup_zone(&self, qname: &str, qtype: QueryType) -> Option<Packet> {
+        let zones = self.zones.read().unwrap();
+        let zone = zones.find(qname)?;
+
+        Some(zone.lookup(qname, qtype))
+    }
+
+    fn build_lookup_packet(&self, qname: &str, qtype: QueryType) -> Packet {
+        let mut req_packet = Packet::new();
+        req_packet.header.id = rand::random();
+        req_packet.header.queries_total = 1;
+        req_packet.header.recursion_desired = true;
+        req_packet
+            .queries
+            .push(Query::new(qname.to_string(), qtype));
+        req_packet
+            .additional_records
+            .push(ResourceRecord::new_opt(UDP_PAYLOAD_SIZE));
+
+        req_packet
+    }
+
+    // Random source ports make responses harder to spoof, on top of the random IDs
+    fn bind_lookup_socket(&self, server: &(IpAddr, u16)) -> Result<UdpSocket> {
+        let mut rng = rand::thread_rng();
+        let unspecified_addr = unspecified_ip_addr(server.0);
+
+        for _ in 0..LOOKUP_BIND_ATTEMPTS {
+            let port = rng.gen_range(LOOKUP_PORT_RANGE);
+
+            match UdpSocket::bind((unspecified_addr, port)) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Fall back to whichever port the OS picks
+        Ok(UdpSocket::bind((unspecified_addr, 0))?)
+    }
+
+    fn lookup(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet> {
+        let lookup_socket = self.bind_lookup_socket(&server)?;
+
+        let req_packet = self.build_lookup_packet(qname, qtype);
+        self.send_packet(
+            req_packet.clone(),
+            &lookup_socket,
+            &server,
+            UDP_PAYLOAD_SIZE,
+        )?;
+
+        let deadline = Instant::now() + self.lookup_timeout;
+        let timed_out = || format!("Timed out waiting for a response from {}", server.0);
+
+        let res_packet = loop {
+            // Unexpected packets mustn't extend the time spent waiting
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(timed_out().into());
+            }
+            lookup_socket.set_read_timeout(Some(remaining))?;
+
+            let (res_packet, src_socket) = match self.receive_packet(&lookup_socket) {
+                Ok(received) => received,
+                Err(e) if is_timeout(e.as_ref()) => return Err(timed_out().into()),
+                // Anyone can send junk to the lookup socket, which mustn't end the lookup early
+                Err(e) if !is_io_error(e.as_ref()) => {
+                    eprintln!("Ignoring malformed response: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if src_socket == SocketAddr::from(server) && res_packet.is_response_to(&req_packet) {
+                break res_packet;
+            }
+
+            eprintln!("Ignoring unexpected response from {}", src_socket);
+        };
+
+        // The full response has to be retrieved over TCP
+        if res_packet.header.truncated_message {
+            return self.lookup_tcp(qname, qtype, server);
+        }
+
+        Ok(res_packet)
+    }
+
+    fn lookup_tcp(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet> {
+        let mut stream =
+            TcpStream::connect_timeout(&SocketAddr::from(server), self.lookup_timeout)?;
+        stream.set_read_timeout(Some(self.lookup_timeout))?;
+        stream.set_write_timeout(Some(self.lookup_timeout))?;
+
+        let req_packet = self.build_lookup_packet(qname, qtype);
+        self.send_tcp_packet(req_packet.clone(), &mut stream)?;
+
+        let res_packet = self.receive_tcp_packet(&mut stream)?;
+        if !res_packet.is_response_to(&req_packet) {
+            return Err(format!("Unexpected response over TCP from {}", server.0).into());
+        }
+
+        Ok(res_packet)
+    }
+
+    // Tries each nameserver in turn until one of them gives a usable response
+    fn lookup_any(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        servers: &[(IpAddr, u16)],
+    ) -> Result<Packet> {
+        let mut last_err = "No nameservers to query".into();
+
+        for _ in 0..self.lookup_attempts {
+            for &server in servers {
+                println!(
+                    "Performing lookup of {:?} {} with ns {}",
+                    qtype, qname, server.0
+                );
+
+                match self
+                    .lookup(qname, qtype, server)
+                    .and_then(|response| Self::check_return_code(response, server.0))
+                {
+                    Ok(response) => return Ok(response),
+                    Err(e) => last_err = e,
+                }
+
+                eprintln!("Lookup with ns {} failed: {}", server.0, last_err);
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // Responses which don't answer the query mean that another server should be tried
+    fn check_return_code(response: Packet, server: IpAddr) -> Result<Packet> {
+        match response.header.return_code {
+            ReturnCode::SERVFAIL | ReturnCode::NOTIMP | ReturnCode::REFUSED => Err(format!(
+                "Nameserver {} responded with {:?}",
+                server, response.header.return_code
+            )
+            .into()),
+            _ => Ok(response),
+        }
+    }
+
+    // The upstream resolvers are trusted with any name in the zone they're used for
+    fn forward_lookup(
+        &self,
+        forwarder: &Forwarder,
+        zone: &str,
+        qname: &str,
+        qtype: QueryType,
+    ) -> Result<Packet> {
+        if let Some(cached_response) = self.lookup_cache(qname, qtype) {
+            println!("Found cached answer for {:?} {}", qtype, qname);
+            return Ok(cached_response);
+        }
+
+        let upstreams = forwarder.get_upstreams();
+        let mut last_err = "No upstream resolvers to forward to".into();
+
+        for _ in 0..self.lookup_attempts {
+            for &upstream in upstreams.iter() {
+                println!("Forwarding lookup of {:?} {} to {}", qtype, qname, upstream);
+
+                let started_at = Instant::now();
+                match self
+                    .lookup(qname, qtype, (upstream.ip(), upstream.port()))
+                    .and_then(|response| Self::check_return_code(response, upstream.ip()))
+                {
+                    Ok(response) => {
+                        forwarder.record_success(upstream, started_at.elapsed());
+
+                        self.cache_response(&response, zone);
+                        self.cache_negative_response(&response, qname, qtype, zone);
+
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        forwarder.record_failure(upstream);
+                        last_err = e;
+                    }
+                }
+
+                eprintln!("Forwarding to {} failed: {}", upstream, last_err);
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // Also returns the zone which the answering nameservers are trusted for
+    fn lookup_upstream(&self, qname: &str, qtype: QueryType) -> Result<(Packet, String)> {
+        // The locks aren't held during the lookup, so that forwarders can be changed meanwhile
+        let conditional_forwarder = self
+            .conditional_forwarders
+            .read()
+            .unwrap()
+            .find(qname)
+            .map(|(domain, forwarder)| (domain.to_string(), Arc::clone(forwarder)));
+        if let Some((domain, forwarder)) = conditional_forwarder {
+            let response = self.forward_lookup(&forwarder, &domain, qname, qtype)?;
+            return Ok((response, domain));
+        }
+
+        let root_zone = "";
+        let forwarder = self.forwarder.read().unwrap().clone();
+        match forwarder {
+            Some(forwarder) => {
+                let response = self.forward_lookup(&forwarder, root_zone, qname, qtype)?;
+                Ok((response, root_zone.to_string()))
+            }
+            None => self.recursive_lookup(qname, qtype),
+        }
+    }
+
+    fn lookup_cache(&self, qname: &str, qtype: QueryType) -> Option<Packet> {
+        let mut cache = self.cache.lock().unwrap();
+
+        if let Some((return_code, soa)) = cache.lookup_negative(qname, qtype) {
+            let mut packet = Packet::new();
+            packet.header.response = true;
+            packet.header.return_code = return_code;
+            packet.authoritative_records.push(soa);
+
+            return Some(packet);
+        }
+
+        let answer_records = cache.lookup(qname, qtype).or_else(|| match qtype {
+            QueryType::CNAME => None,
+            _ => cache.lookup(qname, QueryType::CNAME),
+        })?;
+
+        let mut packet = Packet::new();
+        packet.header.response = true;
+        packet.answer_records = answer_records;
+
+        Some(packet)
+    }
+
+    // Finds the nameservers closest to the name that can be reached using cached records
+    fn find_cached_ns(&self, qname: &str) -> Option<(String, Vec<IpAddr>)> {
+        let mut cache = self.cache.lock().unwrap();
+
+        let labels = split_labels(qname);
+        for i in 0..labels.len() {
+            let zone = labels[i..].join(".");
+
+            let mut ns_ip_addrs = Vec::new();
+            for ns_record in cache.lookup(&zone, QueryType::NS).unwrap_or_default() {
+                let host = match ns_record {
+                    ResourceRecord::NS { host, .. } => host,
+                    _ => continue,
+                };
+
+                for ns_qtype in [QueryType::A, QueryType::AAAA] {
+                    let addr_records = cache.lookup(&host, ns_qtype).unwrap_or_default();
+                    ns_ip_addrs.extend(addr_records.iter().filter_map(ResourceRecord::get_ip_addr));
+                }
+            }
+
+            if !ns_ip_addrs.is_empty() {
+                return Some((zone, ns_ip_addrs));
+            }
+        }
+
+        None
+    }
+
+    fn cache_response(&self, response: &Packet, zone: &str) {
+        // Only trust records which the queried nameserver is authoritative for
+        let records: Vec<ResourceRecord> = response
+            .answer_records
+            .iter()
+            .chain(response.authoritative_records.iter())
+            .chain(response.additional_records.iter())
+            .filter(|record| is_subdomain(record.get_domain(), zone))
+            .cloned()
+            .collect();
+
+        self.cache.lock().unwrap().insert(&records);
+    }
+
+    fn cache_negative_response(
+        &self,
+        response: &Packet,
+        qname: &str,
+        qtype: QueryType,
+        zone: &str,
+    ) {
+        // Answers which are only partially negative (e.g. a CNAME to nowhere) aren't cached
+        if !response.answer_records.is_empty() {
+            return;
+        }
+
+        let soa = match response.get_soa_record() {
+            Some(soa) => soa,
+            None => return,
+        };
+
+        // The SOA has to come from the zone which the name belongs to
+        let soa_domain = soa.get_domain();
+        if !is_subdomain(soa_domain, zone) || !is_subdomain(qname, soa_domain) {
+            return;
+        }
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert_negative(qname, qtype, response.header.return_code, soa);
+    }
+
+    // Follows CNAMEs until the records of the requested type are found, even across zones
+    fn resolve(&self, qname: &str, qtype: QueryType) -> Result<Packet> {
+        let (mut response, mut zone) = self.lookup_upstream(qname, qtype)?;
+        if qtype == QueryType::CNAME {
+            return Ok(response);
+        }
+
+        let mut cname_chain: Vec<ResourceRecord> = Vec::new();
+        let mut name = qname.to_string();
+
+        for _ in 0..MAX_CNAME_CHAIN_LEN {
+            // Follow the part of the chain which the response already contains, as far as the
+            // answering nameservers are authoritative for it. The rest is looked up separately.
+            while let Some(cname) = response.answer_records.iter().find(|record| {
+                record.get_query_type() == QueryType::CNAME
+                    && record.get_domain().eq_ignore_ascii_case(&name)
+                    && is_subdomain(&name, &zone)
+            }) {
+                let host = match cname {
+                    ResourceRecord::CNAME { host, .. } => host.to_lowercase(),
+                    _ => unreachable!(),
+                };
+
+                if host == qname
+                    || cname_chain
+                        .iter()
+                        .any(|record| record.get_domain().eq_ignore_ascii_case(&host))
+                {
+                    return Err(format!("CNAME loop detected at {}", host).into());
+                }
+                if cname_chain.len() >= MAX_CNAME_CHAIN_LEN {
+                    return Err(format!("CNAME chain of {} is too long", qname).into());
+                }
+
+                cname_chain.push(cname.clone());
+                name = host;
+            }
+
+            let chain_complete = is_subdomain(&name, &zone)
+                && response.answer_records.iter().any(|record| {
+                    record.get_query_type() == qtype
+                        && record.get_domain().eq_ignore_ascii_case(&name)
+                });
+            if cname_chain.is_empty()
+                || chain_complete
+                || response.header.return_code != ReturnCode::NOERROR
+            {
+                break;
+            }
+
+            println!("Following CNAME of {} to {}", qname, name);
+            let (next_response, next_zone) = self.lookup_upstream(&name, qtype)?;
+            response = next_response;
+            zone = next_zone;
+        }
+
+        if cname_chain.is_empty() {
+            return Ok(response);
+        }
+
+        // The chain comes first, followed by the records of the name it ends at
+        let final_records: Vec<ResourceRecord> = response
+            .answer_records
+            .drain(..)
+            .filter(|record| {
+                record.get_query_type() == qtype
+                    && record.get_domain().eq_ignore_ascii_case(&name)
+                    && is_subdomain(&name, &zone)
+            })
+            .collect();
+        response.answer_records = cname_chain;
+        response.answer_records.extend(final_records);
+
+        Ok(response)
+    }
+
+    // Also returns the zone of the nameservers which gave the answer
+    fn recursive_lookup(&self, qname: &str, qtype: QueryType) -> Result<(Packet, String)> {
+        // Only records which passed the bailiwick checks are cached
+        if let Some(cached_response) = self.lookup_cache(qname, qtype) {
+            println!("Found cached answer for {:?} {}", qtype, qname);
+            return Ok((cached_response, String::new()));
+        }
+
+        let (mut zone, mut ns_ip_addrs) = self
+            .find_cached_ns(qname)
+            .unwrap_or_else(|| (String::new(), self.get_root_ip_addrs()));
+
+        loop {
+            let response =
+                self.lookup_any(qname, qtype, &self.with_nameserver_port(&ns_ip_addrs))?;
+            self.cache_response(&response, &zone);
+            self.cache_negative_response(&response, qname, qtype, &zone);
+
+            if (!response.answer_records.is_empty()
+                && response.header.return_code == ReturnCode::NOERROR)
+                || response.header.return_code == ReturnCode::NXDOMAIN
+            {
+                return Ok((response, zone));
+            }
+
+            // Referrals have to lead further down the tree, otherwise they can't be trusted
+            zone = match response.get_referral_zone(qname) {
+                Some(new_zone) if new_zone != zone && is_subdomain(new_zone, &zone) => {
+                    new_zone.to_string()
+                }
+                _ => return Ok((response, zone)),
+            };
+
+            let glue_ip_addrs = response.get_ns_from_additional_records(qname);
+            if !glue_ip_addrs.is_empty() {
+                ns_ip_addrs = glue_ip_addrs;
+                continue;
+            }
+
+            // Without glue, the nameservers' addresses have to be looked up separately
+            ns_ip_addrs = Vec::new();
+            for new_ns_host in response.get_ns_hosts(qname) {
+                // AAAA is only looked up when there are no A records, as one address is enough
+                for ns_qtype in [QueryType::A, QueryType::AAAA] {
+                    if !ns_ip_addrs.is_empty() {
+                        break;
+                    }
+
+                    match self.recursive_lookup(new_ns_host, ns_qtype) {
+                        Ok((recursive_response, _)) => {
+                            ns_ip_addrs.extend(recursive_response.get_answer_ip_addrs())
+                        }
+                        Err(e) => eprintln!("Failed to look up ns {}: {}", new_ns_host, e),
+                    }
+                }
+
+                if !ns_ip_addrs.is_empty() {
+                    break;
+                }
+            }
+
+            if ns_ip_addrs.is_empty() {
+                return Ok((response, zone));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::forwarder::UpstreamStrategy;
+    use crate::zone_file;
+
+    const LOCALHOST: &str = "127.0.0.1";
+
+    // Port 0 picks a free port, so tests running in parallel don't collide
+    fn bind_test_socket(addr: &str) -> Result<UdpSocket> {
+        Ok(UdpSocket::bind((addr, 0))?)
+    }
+
+    fn query_packet(qname: &str, qtype: QueryType) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.id = 123;
+        packet.queries.push(Query::new(qname.to_string(), qtype));
+        packet
+    }
+
+    fn send_test_packet(socket: &UdpSocket, mut packet: Packet, dst: SocketAddr) -> Result<()> {
+        let mut buffer = PacketBuffer::new();
+        packet.write_to_buffer(&mut buffer)?;
+        socket.send_to(buffer.get_range(0, buffer.pos())?, dst)?;
+
+        Ok(())
+    }
+
+    fn receive_test_packet(socket: &UdpSocket) -> Result<(Packet, SocketAddr)> {
+        let mut raw_buf = [0; UDP_PAYLOAD_SIZE as usize];
+        let (len, src) = socket.recv_from(&mut raw_buf)?;
+        let mut buffer = PacketBuffer::from_bytes(&raw_buf[..len]);
+
+        Ok((Packet::from_buffer(&mut buffer)?, src))
+    }
+
+    // Sends the query from the client to the resolver, over the client's address family
+    fn query_resolver(resolver: &Resolver, client: &UdpSocket, packet: Packet) -> Result<Packet> {
+        let resolver_addr =
+            SocketAddr::new(client.local_addr()?.ip(), resolver.local_addr()?.port());
+        send_test_packet(client, packet, resolver_addr)?;
+        resolver.handle_query()?;
+
+        Ok(receive_test_packet(client)?.0)
+    }
+
+    // Plays a nameserver answering a single query by echoing it with the given changes
+    fn respond_to_test_query(socket: &UdpSocket, respond: impl FnOnce(&mut Packet)) -> Result<()> {
+        let (mut packet, src) = receive_test_packet(socket)?;
+        packet.header.response = true;
+        packet.additional_records.clear();
+        respond(&mut packet);
+
+        send_test_packet(socket, packet, src)
+    }
+
+    // Resolver whose root and delegated nameservers all answer on the given local port
+    fn local_root_resolver(ns_port: u16) -> Result<Resolver> {
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_nameserver_port(ns_port);
+        resolver.set_root_hints(RootHints::parse(&format!(
+            "
+.                   3600000 NS  a.root-servers.net.
+a.root-servers.net. 3600000 A   {}
+",
+            LOCALHOST
+        ))?);
+
+        Ok(resolver)
+    }
+
+    fn corp_example_zone(serial: u32, www_ip: &str) -> Result<Zone> {
+        let zone_file = format!(
+            "
+$TTL 3600
+@       SOA     ns1 hostmaster {} 3600 900 604800 300
+@       NS      ns1
+ns1     A       192.0.2.1
+www     A       {}
+",
+            serial, www_ip
+        );
+
+        Zone::from_records(
+            "corp.example",
+            zone_file::parse(&zone_file, "corp.example")?,
+        )
+    }
+
+    #[test]
+    fn recursive_lookup() -> Result<()> {
+        /* Arrange */
+        let ns_socket = bind_test_socket(LOCALHOST)?;
+        let resolver = local_root_resolver(ns_socket.local_addr()?.port())?;
+        let client = bind_test_socket(LOCALHOST)?;
+
+        let mut packet = query_packet("google.com", QueryType::A);
+        packet.header.recursion_desired = true;
+
+        // Root nameserver, which refers the query to the google.com nameserver, followed by the
+        // google.com nameserver, both on the same socket
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&ns_socket, |res_packet| {
+                res_packet.authoritative_records.push(ResourceRecord::NS {
+                    domain: "google.com".to_string(),
+                    host: "ns1.google.com".to_string(),
+                    ttl: 300,
+                });
+                res_packet.additional_records.push(ResourceRecord::A {
+                    domain: "ns1.google.com".to_string(),
+                    ip_addr: Ipv4Addr::new(127, 0, 0, 1),
+                    ttl: 300,
+                });
+            })
+            .unwrap();
+
+            respond_to_test_query(&ns_socket, |res_packet| {
+                res_packet.header.authoritative_answer = true;
+                res_packet.answer_records.push(ResourceRecord::A {
+                    domain: "google.com".to_string(),
+                    ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+                    ttl: 300,
+                });
+            })
+            .unwrap();
+        });
+
+        /* Act */
+        let res_packet = query_resolver(&resolver, &client, packet)?;
+        ns_thread.join().unwrap();
+
+        /* Assert */
+        assert!(!res_packet.queries.is_empty());
         assert_eq!(res_packet.queries[0].qname, "google.com");
 
-        assert!(res_packet.answer_records.len() > 0);
+        assert!(!res_packet.answer_records.is_empty());
+        match res_packet.answer_records[0] {
+            ResourceRecord::A {
+                ref domain,
+                ip_addr,
+                ..
+            } => {
+                assert_eq!("google.com", domain);
+                assert_eq!(Ipv4Addr::new(142, 250, 185, 78), ip_addr);
+            }
+            _ => panic!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_edns_version() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+        let client = bind_test_socket(LOCALHOST)?;
+
+        // Query Packet
+        let mut packet = Packet::new();
+        packet.header.id = 123;
+        packet
+            .queries
+            .push(Query::new("google.com".to_string(), QueryType::A));
+        packet.additional_records.push(ResourceRecord::OPT {
+            udp_payload_size: 4096,
+            extended_rcode: 0,
+            version: EDNS_VERSION + 1,
+            dnssec_ok: false,
+            options: Vec::new(),
+        });
+
+        /* Act */
+        let res_packet = query_resolver(&resolver, &client, packet)?;
+
+        /* Assert */
+        assert_eq!(123, res_packet.header.id);
+        assert_eq!(ReturnCode::BADVERS, res_packet.header.return_code);
+        assert!(res_packet.answer_records.is_empty());
+        match res_packet.get_opt_record() {
+            Some(&ResourceRecord::OPT {
+                extended_rcode,
+                version,
+                ..
+            }) => {
+                // Upper 8 bits of the 12-bit code
+                assert_eq!(1, extended_rcode);
+                assert_eq!(EDNS_VERSION, version);
+            }
+            _ => panic!(),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_query() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        // Client
+        let mut stream = TcpStream::connect(resolver.local_addr()?)?;
+
+        // Query Packet
+        let mut packet = Packet::new();
+        packet.header.id = 123;
+        packet
+            .queries
+            .push(Query::new("google.com".to_string(), QueryType::TXT));
+        packet.additional_records.push(ResourceRecord::OPT {
+            udp_payload_size: 4096,
+            extended_rcode: 0,
+            version: EDNS_VERSION + 1,
+            dnssec_ok: false,
+            options: Vec::new(),
+        });
+
+        /* Act */
+        resolver.send_tcp_packet(packet, &mut stream)?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+
+        let server_stream = resolver.accept_tcp_connection()?;
+        resolver.handle_tcp_connection(server_stream)?;
+
+        let res_packet = resolver.receive_tcp_packet(&mut stream)?;
+
+        /* Assert */
+        assert_eq!(123, res_packet.header.id);
+        assert!(res_packet.header.response);
+        assert!(res_packet.get_opt_record().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_over_tcp() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.add_zone(corp_example_zone(1, "192.0.2.10")?);
+
+        // Client
+        let mut stream = TcpStream::connect(resolver.local_addr()?)?;
+
+        /* Act */
+        resolver.send_tcp_packet(query_packet("www.corp.example", QueryType::A), &mut stream)?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+
+        let server_stream = resolver.accept_tcp_connection()?;
+        resolver.handle_tcp_connection(server_stream)?;
+
+        let res_packet = resolver.receive_tcp_packet(&mut stream)?;
+
+        /* Assert */
+        assert_eq!(123, res_packet.header.id);
+        assert_eq!(ReturnCode::NOERROR, res_packet.header.return_code);
+        assert_eq!(
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))],
+            res_packet.get_answer_ip_addrs()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn close_tcp_connection_at_time_limit() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_tcp_connection_timeout(Duration::from_millis(200));
+
+        // Client which trickles in the longest possible query, a byte at a time
+        let mut stream = TcpStream::connect(resolver.local_addr()?)?;
+        let client_thread = std::thread::spawn(move || {
+            for _ in 0..20 {
+                if stream.write_all(&[0xff]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        /* Act */
+        let started_at = Instant::now();
+        let server_stream = resolver.accept_tcp_connection()?;
+        let result = resolver.handle_tcp_connection(server_stream);
+        let elapsed = started_at.elapsed();
+
+        client_thread.join().unwrap();
+
+        /* Assert */
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retry_truncated_response_over_tcp() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        // Nameserver with a UDP socket and a TCP listener on the same port
+        let ns = Resolver::new(LOCALHOST, 0)?;
+        let ns_addr = ns.local_addr()?;
+
+        let answers: Vec<ResourceRecord> = (0..10)
+            .map(|_| ResourceRecord::TXT {
+                domain: "google.com".to_string(),
+                data: vec![vec![b'a'; 200]],
+                ttl: 300,
+            })
+            .collect();
+        let ns_answers = answers.clone();
+
+        // Truncates the UDP response and only gives the full answer over TCP
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&ns.socket, |res_packet| {
+                res_packet.header.truncated_message = true;
+            })
+            .unwrap();
+
+            let mut stream = ns.accept_tcp_connection().unwrap();
+            let mut res_packet = ns.receive_tcp_packet(&mut stream).unwrap();
+            res_packet.header.response = true;
+            res_packet.answer_records = ns_answers;
+            ns.send_tcp_packet(res_packet, &mut stream).unwrap();
+        });
+
+        /* Act */
+        let res_packet =
+            resolver.lookup("google.com", QueryType::TXT, (ns_addr.ip(), ns_addr.port()))?;
+        ns_thread.join().unwrap();
+
+        /* Assert */
+        assert!(!res_packet.header.truncated_message);
+        assert_eq!(answers, res_packet.answer_records);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_oversized_udp_response() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+        let client = bind_test_socket(LOCALHOST)?;
+        let client_addr = client.local_addr()?;
+
+        // Response Packet
+        let mut packet = Packet::new();
+        packet.header.id = 123;
+        packet.header.response = true;
+        packet
+            .queries
+            .push(Query::new("google.com".to_string(), QueryType::TXT));
+        for _ in 0..10 {
+            packet.answer_records.push(ResourceRecord::TXT {
+                domain: "google.com".to_string(),
+                data: vec![vec![b'a'; 100]],
+                ttl: 300,
+            });
+        }
+
+        // Records which can't be written at all aren't dropped to make the response fit
+        let mut invalid_packet = packet.clone();
+        invalid_packet.answer_records.push(ResourceRecord::A {
+            domain: format!("{}.google.com", "a".repeat(64)),
+            ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+            ttl: 300,
+        });
+
+        /* Act */
+        let invalid_result = resolver.send_packet(
+            invalid_packet,
+            &resolver.socket,
+            &(client_addr.ip(), client_addr.port()),
+            MAX_BUF_SIZE as u16,
+        );
+
+        resolver.send_packet(
+            packet,
+            &resolver.socket,
+            &(client_addr.ip(), client_addr.port()),
+            512,
+        )?;
+
+        let mut raw_buf = [0; UDP_PAYLOAD_SIZE as usize];
+        let len = client.recv(&mut raw_buf)?;
+        let mut res_buf = PacketBuffer::from_bytes(&raw_buf[..len]);
+        let res_packet = Packet::from_buffer(&mut res_buf)?;
+
+        /* Assert */
+        assert!(invalid_result.is_err());
+
+        assert!(len <= 512);
+        assert!(res_packet.header.truncated_message);
+        assert_eq!(123, res_packet.header.id);
+        assert_eq!(1, res_packet.queries.len());
+        assert!(res_packet.answer_records.len() < 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cache_only_in_bailiwick_records() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        let mut response = Packet::new();
+        response.answer_records.push(ResourceRecord::A {
+            domain: "www.example.com".to_string(),
+            ip_addr: Ipv4Addr::new(93, 184, 216, 34),
+            ttl: 300,
+        });
+        response.additional_records.push(ResourceRecord::A {
+            domain: "notexample.com".to_string(),
+            ip_addr: Ipv4Addr::new(6, 6, 6, 6),
+            ttl: 300,
+        });
+
+        /* Act */
+        resolver.cache_response(&response, "example.com");
+
+        /* Assert */
+        let mut cache = resolver.cache.lock().unwrap();
+        assert!(cache.lookup("www.example.com", QueryType::A).is_some());
+        assert!(cache.lookup("notexample.com", QueryType::A).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_referrals_to_lookalike_zones() -> Result<()> {
+        /* Arrange */
+        let ns_socket = bind_test_socket(LOCALHOST)?;
+        let mut resolver = local_root_resolver(ns_socket.local_addr()?.port())?;
+        resolver.set_lookup_timeout(Duration::from_millis(100));
+        resolver.set_lookup_attempts(1);
+
+        // Root nameserver, which refers a query for notgoogle.com to the google.com nameservers
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&ns_socket, |res_packet| {
+                res_packet.authoritative_records.push(ResourceRecord::NS {
+                    domain: "google.com".to_string(),
+                    host: "ns1.google.com".to_string(),
+                    ttl: 300,
+                });
+                res_packet.additional_records.push(ResourceRecord::A {
+                    domain: "ns1.google.com".to_string(),
+                    ip_addr: Ipv4Addr::new(127, 0, 0, 1),
+                    ttl: 300,
+                });
+            })
+            .unwrap();
+
+            // The resolver mustn't follow the referral with another query
+            ns_socket
+                .set_read_timeout(Some(Duration::from_millis(300)))
+                .unwrap();
+            receive_test_packet(&ns_socket).is_ok()
+        });
+
+        /* Act */
+        let (res_packet, zone) = resolver.recursive_lookup("notgoogle.com", QueryType::A)?;
+        let followed_referral = ns_thread.join().unwrap();
+
+        /* Assert */
+        assert!(!followed_referral);
+        assert_eq!("", zone);
+        assert!(res_packet.answer_records.is_empty());
+        assert!(res_packet.get_referral_zone("notgoogle.com").is_none());
+        assert!(res_packet
+            .get_ns_from_additional_records("notgoogle.com")
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_from_cache() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        let cached_record = ResourceRecord::A {
+            domain: "google.com".to_string(),
+            ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+            ttl: 300,
+        };
+        resolver.cache.lock().unwrap().insert(&[cached_record]);
+
+        let client = bind_test_socket(LOCALHOST)?;
+
+        /* Act */
+        let res_packet =
+            query_resolver(&resolver, &client, query_packet("google.com", QueryType::A))?;
+
+        /* Assert */
+        assert_eq!(ReturnCode::NOERROR, res_packet.header.return_code);
+        assert_eq!(1, res_packet.answer_records.len());
         match res_packet.answer_records[0] {
-            crate::dns_packet::ResourceRecord::A { ref domain, .. } => {
-                assert_eq!("google.com", domain);
+            ResourceRecord::A { ip_addr, ttl, .. } => {
+                assert_eq!(Ipv4Addr::new(142, 250, 185, 78), ip_addr);
+                assert!(ttl <= 300);
             }
             _ => panic!(),
         }
 
         Ok(())
     }
+
+    #[test]
+    fn negative_answer_from_cache() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        let soa = ResourceRecord::SOA {
+            domain: "google.com".to_string(),
+            mname: "ns1.google.com".to_string(),
+            rname: "dns-admin.google.com".to_string(),
+            serial: 2021070100,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 60,
+        };
+        resolver.cache.lock().unwrap().insert_negative(
+            "nope.google.com",
+            QueryType::A,
+            ReturnCode::NXDOMAIN,
+            &soa,
+        );
+
+        let client = bind_test_socket(LOCALHOST)?;
+
+        /* Act */
+        let res_packet = query_resolver(
+            &resolver,
+            &client,
+            query_packet("nope.google.com", QueryType::A),
+        )?;
+
+        /* Assert */
+        assert_eq!(ReturnCode::NXDOMAIN, res_packet.header.return_code);
+        assert!(res_packet.answer_records.is_empty());
+        assert_eq!(
+            Some(soa.get_domain()),
+            res_packet.get_soa_record().map(|r| r.get_domain())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_spoofed_responses() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        // Nameserver, which first replies with junk, then with a mismatching ID and finally with a
+        // valid response
+        let ns_socket = bind_test_socket(LOCALHOST)?;
+        let ns_addr = ns_socket.local_addr()?;
+        let ns_thread = std::thread::spawn(move || {
+            let (req_packet, src_socket) = receive_test_packet(&ns_socket).unwrap();
+
+            let mut res_packet = req_packet.clone();
+            res_packet.header.response = true;
+            res_packet.additional_records.clear();
+            res_packet.answer_records.push(ResourceRecord::A {
+                domain: "google.com".to_string(),
+                ip_addr: Ipv4Addr::new(6, 6, 6, 6),
+                ttl: 300,
+            });
+
+            let mut spoofed_packet = res_packet.clone();
+            spoofed_packet.header.id = req_packet.header.id.wrapping_add(1);
+
+            res_packet.answer_records[0] = ResourceRecord::A {
+                domain: "google.com".to_string(),
+                ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+                ttl: 300,
+            };
+
+            ns_socket.send_to(&[0xff; 5], src_socket).unwrap();
+            for packet in [spoofed_packet, res_packet] {
+                send_test_packet(&ns_socket, packet, src_socket).unwrap();
+            }
+
+            src_socket
+        });
+
+        /* Act */
+        let res_packet =
+            resolver.lookup("google.com", QueryType::A, (ns_addr.ip(), ns_addr.port()))?;
+        let lookup_src_socket = ns_thread.join().unwrap();
+
+        /* Assert */
+        assert_eq!(
+            vec![&Ipv4Addr::new(142, 250, 185, 78)],
+            res_packet.get_answer_a_records()
+        );
+        assert!(LOOKUP_PORT_RANGE.contains(&lookup_src_socket.port()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_over_to_next_ns_on_timeout() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(100));
+        resolver.set_lookup_attempts(1);
+
+        // Nameserver which never responds
+        let silent_ns_socket = bind_test_socket(LOCALHOST)?;
+        let silent_ns_addr = silent_ns_socket.local_addr()?;
+
+        // Nameserver which refuses the query
+        let refusing_ns_socket = bind_test_socket(LOCALHOST)?;
+        let refusing_ns_addr = refusing_ns_socket.local_addr()?;
+
+        // Working nameserver
+        let ns_socket = bind_test_socket(LOCALHOST)?;
+        let ns_addr = ns_socket.local_addr()?;
+
+        let ns_thread = std::thread::spawn(move || {
+            for (socket, return_code) in [
+                (refusing_ns_socket, ReturnCode::REFUSED),
+                (ns_socket, ReturnCode::NXDOMAIN),
+            ] {
+                respond_to_test_query(&socket, |res_packet| {
+                    res_packet.header.return_code = return_code;
+                })
+                .unwrap();
+            }
+        });
+
+        /* Act */
+        let res_packet = resolver.lookup_any(
+            "nope.google.com",
+            QueryType::A,
+            &[
+                (silent_ns_addr.ip(), silent_ns_addr.port()),
+                (refusing_ns_addr.ip(), refusing_ns_addr.port()),
+                (ns_addr.ip(), ns_addr.port()),
+            ],
+        )?;
+        ns_thread.join().unwrap();
+
+        let exhausted_result = resolver.lookup_any(
+            "nope.google.com",
+            QueryType::A,
+            &[(silent_ns_addr.ip(), silent_ns_addr.port())],
+        );
+
+        /* Assert */
+        assert_eq!(ReturnCode::NXDOMAIN, res_packet.header.return_code);
+        assert!(exhausted_result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn follow_cname_chain() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+
+        let records = vec![
+            ResourceRecord::CNAME {
+                domain: "www.google.com".to_string(),
+                host: "www.l.google.com".to_string(),
+                ttl: 300,
+            },
+            ResourceRecord::CNAME {
+                domain: "www.l.google.com".to_string(),
+                host: "google.com".to_string(),
+                ttl: 300,
+            },
+            ResourceRecord::A {
+                domain: "google.com".to_string(),
+                ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+                ttl: 300,
+            },
+            ResourceRecord::CNAME {
+                domain: "loop1.google.com".to_string(),
+                host: "loop2.google.com".to_string(),
+                ttl: 300,
+            },
+            ResourceRecord::CNAME {
+                domain: "loop2.google.com".to_string(),
+                host: "loop1.google.com".to_string(),
+                ttl: 300,
+            },
+        ];
+        resolver.cache.lock().unwrap().insert(&records);
+
+        /* Act */
+        let response = resolver.resolve("www.google.com", QueryType::A)?;
+        let loop_result = resolver.resolve("loop1.google.com", QueryType::A);
+
+        /* Assert */
+        let answers: Vec<(&str, QueryType)> = response
+            .answer_records
+            .iter()
+            .map(|record| (record.get_domain(), record.get_query_type()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("www.google.com", QueryType::CNAME),
+                ("www.l.google.com", QueryType::CNAME),
+                ("google.com", QueryType::A),
+            ],
+            answers
+        );
+        assert!(loop_result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn forward_to_upstream_resolvers() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(100));
+        resolver.set_lookup_attempts(1);
+
+        // Upstream resolver which never responds
+        let silent_upstream_socket = bind_test_socket(LOCALHOST)?;
+
+        // Working upstream resolver
+        let upstream_socket = bind_test_socket(LOCALHOST)?;
+
+        let upstreams = vec![
+            silent_upstream_socket.local_addr()?,
+            upstream_socket.local_addr()?,
+        ];
+        resolver.set_forwarder(Forwarder::new(upstreams.clone(), UpstreamStrategy::Fastest));
+
+        let answer = ResourceRecord::A {
+            domain: "google.com".to_string(),
+            ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+            ttl: 300,
+        };
+        let upstream_answer = answer.clone();
+
+        let upstream_thread = std::thread::spawn(move || {
+            respond_to_test_query(&upstream_socket, |res_packet| {
+                assert!(res_packet.header.recursion_desired);
+                res_packet.answer_records.push(upstream_answer);
+            })
+            .unwrap();
+        });
+
+        /* Act */
+        let res_packet = resolver.resolve("google.com", QueryType::A)?;
+        upstream_thread.join().unwrap();
+
+        // The second lookup is answered from the cache, as the upstream only responds once
+        let cached_packet = resolver.resolve("google.com", QueryType::A)?;
+
+        /* Assert */
+        assert_eq!(vec![answer], res_packet.answer_records);
+        assert_eq!(1, cached_packet.answer_records.len());
+
+        // The silent upstream is now tried last
+        let forwarder = resolver.forwarder.read().unwrap();
+        assert_eq!(
+            vec![upstreams[1], upstreams[0]],
+            forwarder.as_ref().unwrap().get_upstreams()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn replace_forwarder_during_lookup() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(500));
+        resolver.set_lookup_attempts(1);
+
+        // Upstream resolver which never responds
+        let silent_upstream_socket = bind_test_socket(LOCALHOST)?;
+        resolver.set_forwarder(Forwarder::new(
+            vec![silent_upstream_socket.local_addr()?],
+            UpstreamStrategy::Fastest,
+        ));
+
+        let resolver = Arc::new(resolver);
+        let lookup_resolver = Arc::clone(&resolver);
+        let lookup_thread = std::thread::spawn(move || {
+            lookup_resolver.resolve("google.com", QueryType::A).is_err()
+        });
+
+        /* Act */
+        // The lookup is in flight once the upstream has received it
+        receive_test_packet(&silent_upstream_socket)?;
+        let forwarder_lock_free = resolver.forwarder.try_write().is_ok();
+        let lookup_failed = lookup_thread.join().unwrap();
+
+        /* Assert */
+        assert!(forwarder_lock_free);
+        assert!(lookup_failed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn forward_conditionally_by_domain() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(100));
+        resolver.set_lookup_attempts(1);
+
+        // Internal nameserver
+        let internal_ns_socket = bind_test_socket(LOCALHOST)?;
+
+        resolver.add_conditional_forwarder(
+            "Corp.Example.",
+            Forwarder::new(
+                vec![internal_ns_socket.local_addr()?],
+                UpstreamStrategy::Ordered,
+            ),
+        );
+
+        let answer = ResourceRecord::A {
+            domain: "intranet.corp.example".to_string(),
+            ip_addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 300,
+        };
+        let ns_answer = answer.clone();
+
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&internal_ns_socket, |res_packet| {
+                res_packet.answer_records.push(ns_answer);
+                // The internal nameserver isn't trusted with names outside of its domain
+                res_packet.additional_records.push(ResourceRecord::A {
+                    domain: "google.com".to_string(),
+                    ip_addr: Ipv4Addr::new(10, 0, 0, 2),
+                    ttl: 300,
+                });
+            })
+            .unwrap();
+        });
+
+        /* Act */
+        let res_packet = resolver.resolve("intranet.corp.example", QueryType::A)?;
+        ns_thread.join().unwrap();
+
+        /* Assert */
+        assert_eq!(vec![answer], res_packet.answer_records);
+
+        let mut cache = resolver.cache.lock().unwrap();
+        assert!(cache
+            .lookup("intranet.corp.example", QueryType::A)
+            .is_some());
+        assert!(cache.lookup("google.com", QueryType::A).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_conditional_forwarder_during_lookup() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(500));
+        resolver.set_lookup_attempts(1);
+
+        // Internal nameserver which never responds
+        let silent_ns_socket = bind_test_socket(LOCALHOST)?;
+        resolver.add_conditional_forwarder(
+            "corp.example",
+            Forwarder::new(
+                vec![silent_ns_socket.local_addr()?],
+                UpstreamStrategy::Ordered,
+            ),
+        );
+
+        let resolver = Arc::new(resolver);
+        let lookup_resolver = Arc::clone(&resolver);
+        let lookup_thread = std::thread::spawn(move || {
+            lookup_resolver
+                .resolve("intranet.corp.example", QueryType::A)
+                .is_err()
+        });
+
+        /* Act */
+        // The lookup is in flight once the nameserver has received it
+        receive_test_packet(&silent_ns_socket)?;
+        let forwarders_lock_free = resolver.conditional_forwarders.try_write().is_ok();
+        let lookup_failed = lookup_thread.join().unwrap();
+
+        /* Assert */
+        assert!(forwarders_lock_free);
+        assert!(lookup_failed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn look_up_out_of_bailiwick_cname_targets() -> Result<()> {
+        /* Arrange */
+        let mut resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.set_lookup_timeout(Duration::from_millis(500));
+        resolver.set_lookup_attempts(1);
+
+        // Internal nameserver, which isn't trusted with names outside of its domain
+        let internal_ns_socket = bind_test_socket(LOCALHOST)?;
+        resolver.add_conditional_forwarder(
+            "corp.example",
+            Forwarder::new(
+                vec![internal_ns_socket.local_addr()?],
+                UpstreamStrategy::Ordered,
+            ),
+        );
+
+        // Upstream resolver for everything else, which gives up rather than hang the test
+        let upstream_socket = bind_test_socket(LOCALHOST)?;
+        upstream_socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+        resolver.set_forwarder(Forwarder::new(
+            vec![upstream_socket.local_addr()?],
+            UpstreamStrategy::Ordered,
+        ));
+
+        let cname = ResourceRecord::CNAME {
+            domain: "intranet.corp.example".to_string(),
+            host: "www.google.com".to_string(),
+            ttl: 300,
+        };
+        let answer = ResourceRecord::A {
+            domain: "www.google.com".to_string(),
+            ip_addr: Ipv4Addr::new(142, 250, 185, 78),
+            ttl: 300,
+        };
+
+        let ns_cname = cname.clone();
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&internal_ns_socket, |res_packet| {
+                res_packet.answer_records.push(ns_cname);
+                res_packet.answer_records.push(ResourceRecord::A {
+                    domain: "www.google.com".to_string(),
+                    ip_addr: Ipv4Addr::new(6, 6, 6, 6),
+                    ttl: 300,
+                });
+            })
+            .unwrap();
+        });
+
+        let upstream_answer = answer.clone();
+        let upstream_thread = std::thread::spawn(move || {
+            respond_to_test_query(&upstream_socket, |res_packet| {
+                assert_eq!("www.google.com", res_packet.queries[0].qname);
+                res_packet.answer_records.push(upstream_answer);
+            })
+            .unwrap();
+        });
+
+        /* Act */
+        let res_packet = resolver.resolve("intranet.corp.example", QueryType::A)?;
+        ns_thread.join().unwrap();
+        upstream_thread.join().unwrap();
+
+        /* Assert */
+        assert_eq!(vec![cname, answer], res_packet.answer_records);
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_and_look_up_over_ipv6() -> Result<()> {
+        /* Arrange */
+        let localhost_ipv6_str = "::1";
+
+        // Resolver listening on all IPv4 and IPv6 addresses
+        let resolver = Resolver::new("::", 0)?;
+
+        let cached_record = ResourceRecord::AAAA {
+            domain: "google.com".to_string(),
+            ip_addr: "2a00:1450:4001:80b::200e".parse::<Ipv6Addr>()?,
+            ttl: 300,
+        };
+        resolver.cache.lock().unwrap().insert(&[cached_record]);
+
+        // Clients
+        let ipv4_client = bind_test_socket(LOCALHOST)?;
+        let ipv6_client = bind_test_socket(localhost_ipv6_str)?;
+
+        // Nameserver
+        let ns_socket = bind_test_socket(localhost_ipv6_str)?;
+        let ns_addr = ns_socket.local_addr()?;
+
+        let ns_thread = std::thread::spawn(move || {
+            respond_to_test_query(&ns_socket, |res_packet| {
+                res_packet.header.return_code = ReturnCode::NXDOMAIN;
+            })
+            .unwrap();
+        });
+
+        /* Act */
+        let mut res_packets = Vec::new();
+        for client in [&ipv4_client, &ipv6_client] {
+            res_packets.push(query_resolver(
+                &resolver,
+                client,
+                query_packet("google.com", QueryType::AAAA),
+            )?);
+        }
+
+        let ns_res_packet = resolver.lookup(
+            "nope.google.com",
+            QueryType::A,
+            (ns_addr.ip(), ns_addr.port()),
+        )?;
+        ns_thread.join().unwrap();
+
+        /* Assert */
+        for res_packet in res_packets {
+            assert_eq!(123, res_packet.header.id);
+            assert_eq!(1, res_packet.answer_records.len());
+        }
+        assert_eq!(ReturnCode::NXDOMAIN, ns_res_packet.header.return_code);
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_from_zone() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.add_zone(corp_example_zone(1, "192.0.2.10")?);
+
+        let client = bind_test_socket(LOCALHOST)?;
+
+        /* Act */
+        let mut res_packets = Vec::new();
+        for qname in ["ns1.corp.example", "nope.corp.example"] {
+            res_packets.push(query_resolver(
+                &resolver,
+                &client,
+                query_packet(qname, QueryType::A),
+            )?);
+        }
+
+        /* Assert */
+        let (answer, nxdomain) = (&res_packets[0], &res_packets[1]);
+
+        assert!(answer.header.authoritative_answer);
+        assert_eq!(ReturnCode::NOERROR, answer.header.return_code);
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            answer.answer_records[0].get_ip_addr()
+        );
+
+        assert!(nxdomain.header.authoritative_answer);
+        assert_eq!(ReturnCode::NXDOMAIN, nxdomain.header.return_code);
+        assert_eq!(
+            QueryType::SOA,
+            nxdomain.authoritative_records[0].get_query_type()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_zone() -> Result<()> {
+        /* Arrange */
+        let resolver = Arc::new(Resolver::new(LOCALHOST, 0)?);
+
+        let soa = ResourceRecord::SOA {
+            domain: "corp.example".to_string(),
+            mname: "ns1.corp.example".to_string(),
+            rname: "hostmaster.corp.example".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+            ttl: 3600,
+        };
+        let host_count = 1000;
+        let mut records = vec![soa.clone()];
+        for i in 0..host_count {
+            records.push(ResourceRecord::A {
+                domain: format!("host-{}.corp.example", i),
+                ip_addr: Ipv4Addr::new(10, 0, (i / 256) as u8, (i % 256) as u8),
+                ttl: 3600,
+            });
+        }
+        resolver.add_zone(Zone::from_records("corp.example", records)?);
+        resolver.allow_zone_transfers_to(IpAddr::V4(LOCALHOST.parse()?));
+
+        // Client
+        let mut stream = TcpStream::connect(resolver.local_addr()?)?;
+
+        let server_resolver = Arc::clone(&resolver);
+        let server_thread = std::thread::spawn(move || {
+            let server_stream = server_resolver.accept_tcp_connection().unwrap();
+            server_resolver
+                .handle_tcp_connection(server_stream)
+                .unwrap();
+        });
+
+        /* Act */
+        let mut refused_packet = Packet::new();
+        refused_packet
+            .queries
+            .push(Query::new("nope.example".to_string(), QueryType::AXFR));
+        resolver.send_tcp_packet(refused_packet, &mut stream)?;
+        let refused_res_packet = resolver.receive_tcp_packet(&mut stream)?;
+
+        let mut packet = Packet::new();
+        packet.header.id = 123;
+        packet
+            .queries
+            .push(Query::new("corp.example".to_string(), QueryType::AXFR));
+        resolver.send_tcp_packet(packet, &mut stream)?;
+
+        let mut res_packets = Vec::new();
+        let mut transfer_records: Vec<ResourceRecord> = Vec::new();
+        while transfer_records.len() < 2 || transfer_records.last() != Some(&soa) {
+            let res_packet = resolver.receive_tcp_packet(&mut stream)?;
+            transfer_records.extend(res_packet.answer_records.iter().cloned());
+            res_packets.push(res_packet);
+        }
+
+        stream.shutdown(std::net::Shutdown::Write)?;
+        server_thread.join().unwrap();
+
+        /* Assert */
+        assert_eq!(ReturnCode::REFUSED, refused_res_packet.header.return_code);
+
+        assert!(res_packets.len() > 1);
+        assert!(res_packets
+            .iter()
+            .all(|res_packet| res_packet.header.id == 123
+                && res_packet.header.authoritative_answer
+                && res_packet.header.return_code == ReturnCode::NOERROR));
+
+        assert_eq!(host_count + 2, transfer_records.len());
+        assert_eq!(soa, transfer_records[0]);
+
+        assert!(resolver.is_transfer_allowed(IpAddr::V6("::ffff:127.0.0.1".parse()?)));
+        assert!(!resolver.is_transfer_allowed(IpAddr::V6("::1".parse()?)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_ixfr_over_udp_with_soa() -> Result<()> {
+        /* Arrange */
+        let resolver = Resolver::new(LOCALHOST, 0)?;
+        resolver.add_zone(corp_example_zone(2, "192.0.2.10")?);
+        resolver.allow_zone_transfers_to(IpAddr::V4(LOCALHOST.parse()?));
+
+        let client = bind_test_socket(LOCALHOST)?;
+
+        /* Act */
+        let ixfr_res_packet = query_resolver(
+            &resolver,
+            &client,
+            query_packet("corp.example", QueryType::IXFR),
+        )?;
+        let axfr_res_packet = query_resolver(
+            &resolver,
+            &client,
+            query_packet("corp.example", QueryType::AXFR),
+        )?;
+        let unknown_zone_res_packet = query_resolver(
+            &resolver,
+            &client,
+            query_packet("nope.example", QueryType::IXFR),
+        )?;
+
+        /* Assert */
+        assert!(ixfr_res_packet.header.authoritative_answer);
+        assert_eq!(ReturnCode::NOERROR, ixfr_res_packet.header.return_code);
+        assert_eq!(1, ixfr_res_packet.answer_records.len());
+        assert_eq!(Some(2), get_soa_serial(&ixfr_res_packet.answer_records[0]));
+
+        assert_eq!(ReturnCode::REFUSED, axfr_res_packet.header.return_code);
+        assert!(axfr_res_packet.answer_records.is_empty());
+
+        assert_eq!(
+            ReturnCode::REFUSED,
+            unknown_zone_res_packet.header.return_code
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pull_secondary_zone() -> Result<()> {
+        /* Arrange */
+        // Primary
+        let primary = Arc::new(Resolver::new(LOCALHOST, 0)?);
+        let primary_addr = primary.local_addr()?;
+        primary.add_zone(corp_example_zone(1, "192.0.2.10")?);
+        primary.allow_zone_transfers_to(IpAddr::V4(LOCALHOST.parse()?));
+
+        // The initial AXFR and the later IXFR, so a fallback to AXFR would time out
+        let tcp_primary = Arc::clone(&primary);
+        let tcp_thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let stream = tcp_primary.accept_tcp_connection().unwrap();
+                tcp_primary.handle_tcp_connection(stream).unwrap();
+            }
+        });
+
+        // The SOA query before the IXFR
+        let udp_primary = Arc::clone(&primary);
+        let udp_thread = std::thread::spawn(move || udp_primary.handle_query().unwrap());
+
+        // Secondary
+        let mut secondary = Resolver::new(LOCALHOST, 0)?;
+        secondary.set_lookup_timeout(Duration::from_millis(500));
+        secondary.add_secondary_zone("corp.example", primary_addr);
+
+        /* Act */
+        let next_refresh_at = secondary.refresh_secondary_zones();
+        let first_answer = secondary.lookup_zone("www.corp.example", QueryType::A);
+
+        primary.add_zone(corp_example_zone(2, "192.0.2.20")?);
+
+        // Refreshed directly, as the SOA's refresh timer is far from running out
+        let soa = {
+            let secondary_zones = secondary.secondary_zones.lock().unwrap();
+            secondary.refresh_secondary_zone(&secondary_zones[0])?
+        };
+        let second_answer = secondary.lookup_zone("www.corp.example", QueryType::A);
+
+        tcp_thread.join().unwrap();
+        udp_thread.join().unwrap();
+
+        /* Assert */
+        let next_refresh_in = next_refresh_at.unwrap() - Instant::now();
+        assert!(next_refresh_in > Duration::from_secs(3500));
+
+        let first_answer = first_answer.unwrap();
+        assert!(first_answer.header.authoritative_answer);
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))),
+            first_answer.answer_records[0].get_ip_addr()
+        );
+
+        assert_eq!(Some(2), get_soa_serial(&soa));
+        assert_eq!(
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20))],
+            second_answer.unwrap().get_answer_ip_addrs()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn notify_secondary_of_zone_change() -> Result<()> {
+        /* Arrange */
+        // Secondary
+        let secondary = Resolver::new(LOCALHOST, 0)?;
+
+        // Primary
+        let primary = Arc::new(Resolver::new(LOCALHOST, 0)?);
+        primary.add_zone(corp_example_zone(1, "192.0.2.10")?);
+        primary.allow_zone_transfers_to(IpAddr::V4(LOCALHOST.parse()?));
+        primary.notify_zone_changes_to(secondary.local_addr()?);
+
+        let tcp_primary = Arc::clone(&primary);
+        let tcp_thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let stream = tcp_primary.accept_tcp_connection().unwrap();
+                tcp_primary.handle_tcp_connection(stream).unwrap();
+            }
+        });
+
+        let udp_primary = Arc::clone(&primary);
+        let udp_thread = std::thread::spawn(move || udp_primary.handle_query().unwrap());
+
+        secondary.add_secondary_zone("corp.example", primary.local_addr()?);
+        secondary.refresh_secondary_zones();
+
+        let client = bind_test_socket(LOCALHOST)?;
+
+        /* Act */
+        let mut unknown_notify_packet = query_packet("nope.example", QueryType::SOA);
+        unknown_notify_packet.header.opcode = Opcode::NOTIFY;
+        let refused_res_packet = query_resolver(&secondary, &client, unknown_notify_packet)?;
+
+        let is_due_before_notify = secondary.secondary_zones.lock().unwrap()[0].is_due();
+
+        // Changing the zone makes the primary notify the secondary
+        primary.add_zone(corp_example_zone(2, "192.0.2.20")?);
+        secondary.handle_query()?;
+
+        let is_due_after_notify = secondary.secondary_zones.lock().unwrap()[0].is_due();
+        secondary.refresh_secondary_zones();
+        let answer = secondary.lookup_zone("www.corp.example", QueryType::A);
+
+        // Without a newer serial, the secondary wouldn't transfer the change anyway
+        primary.add_zone(corp_example_zone(2, "192.0.2.30")?);
+        secondary
+            .socket
+            .set_read_timeout(Some(Duration::from_millis(500)))?;
+        let unchanged_serial_notify = secondary.handle_query();
+
+        tcp_thread.join().unwrap();
+        udp_thread.join().unwrap();
+
+        /* Assert */
+        assert_eq!(Opcode::NOTIFY, refused_res_packet.header.opcode);
+        assert!(refused_res_packet.header.response);
+        assert_eq!(ReturnCode::REFUSED, refused_res_packet.header.return_code);
+
+        assert!(!is_due_before_notify);
+        assert!(is_due_after_notify);
+        assert_eq!(
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 20))],
+            answer.unwrap().get_answer_ip_addrs()
+        );
+        assert!(unchanged_serial_notify.is_err());
+
+        Ok(())
+    }
 }
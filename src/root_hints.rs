@@ -0,0 +1,190 @@
+use crate::dns_packet::ResourceRecord;
+use crate::zone_file;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Taken from https://www.internic.net/domain/named.root
+const BUILT_IN_ROOT_SERVERS: [(&str, [u8; 4], &str); 13] = [
+    ("a.root-servers.net", [198, 41, 0, 4], "2001:503:ba3e::2:30"),
+    ("b.root-servers.net", [170, 247, 170, 2], "2801:1b8:10::b"),
+    ("c.root-servers.net", [192, 33, 4, 12], "2001:500:2::c"),
+    ("d.root-servers.net", [199, 7, 91, 13], "2001:500:2d::d"),
+    ("e.root-servers.net", [192, 203, 230, 10], "2001:500:a8::e"),
+    ("f.root-servers.net", [192, 5, 5, 241], "2001:500:2f::f"),
+    ("g.root-servers.net", [192, 112, 36, 4], "2001:500:12::d0d"),
+    ("h.root-servers.net", [198, 97, 190, 53], "2001:500:1::53"),
+    ("i.root-servers.net", [192, 36, 148, 17], "2001:7fe::53"),
+    (
+        "j.root-servers.net",
+        [192, 58, 128, 30],
+        "2001:503:c27::2:30",
+    ),
+    ("k.root-servers.net", [193, 0, 14, 129], "2001:7fd::1"),
+    ("l.root-servers.net", [199, 7, 83, 42], "2001:500:9f::42"),
+    ("m.root-servers.net", [202, 12, 27, 33], "2001:dc3::35"),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootServer {
+    pub host: String,
+    pub ipv4_addr: Option<Ipv4Addr>,
+    pub ipv6_addr: Option<Ipv6Addr>,
+}
+
+pub struct RootHints {
+    servers: Vec<RootServer>,
+    next_server: AtomicUsize,
+}
+
+impl RootHints {
+    pub fn new() -> Self {
+        let servers = BUILT_IN_ROOT_SERVERS
+            .iter()
+            .map(|&(host, ipv4_octets, ipv6_str)| RootServer {
+                host: host.to_string(),
+                ipv4_addr: Some(Ipv4Addr::from(ipv4_octets)),
+                ipv6_addr: ipv6_str.parse::<Ipv6Addr>().ok(),
+            })
+            .collect();
+
+        Self::from_servers(servers)
+    }
+
+    fn from_servers(servers: Vec<RootServer>) -> Self {
+        RootHints {
+            servers,
+            next_server: AtomicUsize::new(0),
+        }
+    }
+
+    // Builds the hints from the root NS records and the addresses of their hosts
+    pub fn from_records(records: &[ResourceRecord]) -> Self {
+        let mut servers: Vec<RootServer> = records
+            .iter()
+            .filter_map(|record| match record {
+                ResourceRecord::NS { domain, host, .. } if domain.is_empty() => Some(RootServer {
+                    host: host.to_lowercase(),
+                    ipv4_addr: None,
+                    ipv6_addr: None,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        for server in servers.iter_mut() {
+            for record in records {
+                match record {
+                    ResourceRecord::A {
+                        domain, ip_addr, ..
+                    } if domain.to_lowercase() == server.host => server.ipv4_addr = Some(*ip_addr),
+                    ResourceRecord::AAAA {
+                        domain, ip_addr, ..
+                    } if domain.to_lowercase() == server.host => server.ipv6_addr = Some(*ip_addr),
+                    _ => {}
+                }
+            }
+        }
+
+        Self::from_servers(servers)
+    }
+
+    // Parses hints in the format of the named.root file distributed by IANA, a master file
+    pub fn parse(hints: &str) -> Result<Self> {
+        Self::from_parsed_records(&zone_file::parse(hints, "")?)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_parsed_records(&zone_file::parse_file(path, "")?)
+    }
+
+    fn from_parsed_records(records: &[ResourceRecord]) -> Result<Self> {
+        let root_hints = Self::from_records(records);
+        if root_hints.get_ip_addrs().is_empty() {
+            return Err("Root hints don't contain any addresses".into());
+        }
+
+        Ok(root_hints)
+    }
+
+    pub fn get_servers(&self) -> &[RootServer] {
+        &self.servers
+    }
+
+    // Every call starts with a different server so that the load is spread across them
+    pub fn get_ip_addrs(&self) -> Vec<IpAddr> {
+        if self.servers.is_empty() {
+            return Vec::new();
+        }
+
+        let start = self.next_server.fetch_add(1, Ordering::Relaxed) % self.servers.len();
+
+        self.servers[start..]
+            .iter()
+            .chain(self.servers[..start].iter())
+            .flat_map(|server| {
+                let ipv4_addr = server.ipv4_addr.map(IpAddr::V4);
+                let ipv6_addr = server.ipv6_addr.map(IpAddr::V6);
+                ipv4_addr.into_iter().chain(ipv6_addr)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_root_hints() {
+        /* Arrange */
+        let root_hints = RootHints::new();
+
+        /* Act */
+        let first_ip_addrs = root_hints.get_ip_addrs();
+        let second_ip_addrs = root_hints.get_ip_addrs();
+
+        /* Assert */
+        assert_eq!(13, root_hints.get_servers().len());
+        assert_eq!(26, first_ip_addrs.len());
+        assert_eq!(IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)), first_ip_addrs[0]);
+        // The next lookup starts from the next server
+        assert_eq!(first_ip_addrs[2], second_ip_addrs[0]);
+    }
+
+    #[test]
+    fn parse_root_hints() -> Result<()> {
+        /* Arrange */
+        let hints = "
+; This file holds the information on root name servers
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+;
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2
+";
+
+        /* Act */
+        let root_hints = RootHints::parse(hints)?;
+
+        /* Assert */
+        let servers = root_hints.get_servers();
+        assert_eq!(2, servers.len());
+        assert_eq!(
+            RootServer {
+                host: "a.root-servers.net".to_string(),
+                ipv4_addr: Some(Ipv4Addr::new(198, 41, 0, 4)),
+                ipv6_addr: Some("2001:503:ba3e::2:30".parse::<Ipv6Addr>()?),
+            },
+            servers[0]
+        );
+        assert_eq!(None, servers[1].ipv6_addr);
+
+        assert!(RootHints::parse("; Nothing but comments").is_err());
+
+        Ok(())
+    }
+}
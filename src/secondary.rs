@@ -0,0 +1,334 @@
+use crate::dns_packet::{QueryType, ResourceRecord};
+use crate::zone::{get_soa_serial, is_serial_newer};
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Used until the zone's own SOA is known
+const DEFAULT_RETRY: Duration = Duration::from_secs(60);
+
+// A zone which is kept in sync with its primary server, following the timers of its SOA
+// (RFC 1035 section 4.3.5)
+#[derive(Clone)]
+pub struct SecondaryZone {
+    origin: String,
+    primary: SocketAddr,
+    next_refresh_at: Instant,
+    refresh_requested: bool,
+    retry: Duration,
+    expires_at: Option<Instant>,
+}
+
+impl SecondaryZone {
+    pub fn new(origin: &str, primary: SocketAddr) -> Self {
+        SecondaryZone {
+            origin: origin.trim_end_matches('.').to_lowercase(),
+            primary,
+            next_refresh_at: Instant::now(),
+            refresh_requested: false,
+            retry: DEFAULT_RETRY,
+            expires_at: None,
+        }
+    }
+
+    pub fn get_origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn get_primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    pub fn get_next_refresh_at(&self) -> Instant {
+        self.next_refresh_at
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.refresh_requested || self.next_refresh_at <= Instant::now()
+    }
+
+    // Makes the zone due right away, e.g. when the primary says that it changed (RFC 1996)
+    pub fn request_refresh(&mut self) {
+        self.refresh_requested = true;
+    }
+
+    // Requests made from here on need another refresh, as this one may have missed the change
+    pub fn start_refresh(&mut self) {
+        self.refresh_requested = false;
+    }
+
+    pub fn record_refresh(&mut self, soa: &ResourceRecord) {
+        self.record_refresh_at(soa, Instant::now());
+    }
+
+    fn record_refresh_at(&mut self, soa: &ResourceRecord, now: Instant) {
+        if let ResourceRecord::SOA {
+            refresh,
+            retry,
+            expire,
+            ..
+        } = *soa
+        {
+            self.next_refresh_at = now + Duration::from_secs(refresh as u64);
+            self.retry = Duration::from_secs(retry as u64);
+            self.expires_at = Some(now + Duration::from_secs(expire as u64));
+        }
+    }
+
+    // Returns whether the zone has gone without a refresh for so long that it's expired
+    pub fn record_failure(&mut self) -> bool {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&mut self, now: Instant) -> bool {
+        self.next_refresh_at = now + self.retry;
+
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+// Checks whether all messages of an AXFR or IXFR response have been received, given the serial
+// which the secondary already has
+pub fn is_transfer_complete(records: &[ResourceRecord], known_serial: Option<u32>) -> bool {
+    let serial = match records.first().and_then(get_soa_serial) {
+        Some(serial) => serial,
+        None => return false,
+    };
+
+    // A lone SOA means that the secondary is up to date
+    if records.len() == 1 {
+        return match known_serial {
+            Some(known_serial) => !is_serial_newer(serial, known_serial),
+            None => false,
+        };
+    }
+
+    // Both transfer formats end with the new SOA, which always makes for an even number of them
+    let soa_count = records
+        .iter()
+        .filter(|record| record.get_query_type() == QueryType::SOA)
+        .count();
+
+    records.last().and_then(get_soa_serial) == Some(serial) && soa_count % 2 == 0
+}
+
+// Builds the zone's new records from a complete AXFR or IXFR response (RFC 1995 section 4)
+pub fn apply_transfer(
+    current_records: &[ResourceRecord],
+    transfer_records: &[ResourceRecord],
+    qtype: QueryType,
+) -> Result<Vec<ResourceRecord>> {
+    let is_soa = |record: &ResourceRecord| record.get_query_type() == QueryType::SOA;
+
+    if transfer_records.len() == 1 {
+        return Ok(current_records.to_vec());
+    }
+    if transfer_records.len() < 2 || !is_soa(&transfer_records[0]) {
+        return Err("Zone transfer doesn't start with an SOA record".into());
+    }
+
+    // Only an IXFR response whose second record is the SOA of the secondary's version lists
+    // changes. Anything else holds the whole zone, even when it's empty and thus just two SOAs.
+    let current_soa = current_records.iter().find(|record| is_soa(record));
+    let is_incremental = qtype == QueryType::IXFR
+        && is_soa(&transfer_records[1])
+        && get_soa_serial(&transfer_records[1]) == current_soa.and_then(get_soa_serial);
+
+    // Full transfers contain the zone's records between two SOAs
+    let end = transfer_records.len() - 1;
+    if !is_incremental {
+        if transfer_records[1..end].iter().any(is_soa) {
+            return Err("Full zone transfer contains more than two SOA records".into());
+        }
+        return Ok(transfer_records[..end].to_vec());
+    }
+
+    let mut soa = current_soa.unwrap().clone();
+    let mut records: Vec<ResourceRecord> = current_records
+        .iter()
+        .filter(|record| !is_soa(record))
+        .cloned()
+        .collect();
+
+    let mut i = 1;
+    while i < end {
+        // Each change starts with the SOA of the version it applies to and the deleted records
+        if get_soa_serial(&transfer_records[i]) != get_soa_serial(&soa) {
+            return Err("Zone transfer changes a different version of the zone".into());
+        }
+        i += 1;
+
+        while i < end && !is_soa(&transfer_records[i]) {
+            let pos = records
+                .iter()
+                .position(|record| record == &transfer_records[i])
+                .ok_or("Zone transfer deletes a record which doesn't exist")?;
+            records.remove(pos);
+            i += 1;
+        }
+
+        // It's then followed by the SOA of the new version and the added records
+        if i >= end {
+            return Err("Zone transfer change is missing its new SOA record".into());
+        }
+        soa = transfer_records[i].clone();
+        i += 1;
+
+        while i < end && !is_soa(&transfer_records[i]) {
+            records.push(transfer_records[i].clone());
+            i += 1;
+        }
+    }
+
+    if get_soa_serial(&soa) != get_soa_serial(&transfer_records[0]) {
+        return Err("Zone transfer doesn't lead to the latest version of the zone".into());
+    }
+
+    records.insert(0, soa);
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn soa(serial: u32) -> ResourceRecord {
+        ResourceRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns1.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+            ttl: 3600,
+        }
+    }
+
+    fn a_record(domain: &str, last_octet: u8) -> ResourceRecord {
+        ResourceRecord::A {
+            domain: domain.to_string(),
+            ip_addr: Ipv4Addr::new(192, 0, 2, last_octet),
+            ttl: 3600,
+        }
+    }
+
+    #[test]
+    fn follow_soa_timers() -> Result<()> {
+        /* Arrange */
+        let mut secondary_zone = SecondaryZone::new("Example.com.", "127.0.0.1:53".parse()?);
+        let now = Instant::now();
+
+        /* Act */
+        let is_due_initially = secondary_zone.is_due();
+
+        let expired_before_transfer = secondary_zone.record_failure_at(now);
+        let first_retry_at = secondary_zone.get_next_refresh_at();
+
+        secondary_zone.record_refresh_at(&soa(1), now);
+        let refresh_at = secondary_zone.get_next_refresh_at();
+
+        let expired_after_retry = secondary_zone.record_failure_at(now + Duration::from_secs(3600));
+        let retry_at = secondary_zone.get_next_refresh_at();
+        let expired_after_expire =
+            secondary_zone.record_failure_at(now + Duration::from_secs(604800));
+
+        secondary_zone.record_refresh_at(&soa(1), Instant::now());
+        let is_due_after_refresh = secondary_zone.is_due();
+        secondary_zone.request_refresh();
+        let is_due_after_request = secondary_zone.is_due();
+        secondary_zone.start_refresh();
+        let is_due_after_start = secondary_zone.is_due();
+
+        /* Assert */
+        assert_eq!("example.com", secondary_zone.get_origin());
+        assert!(is_due_initially);
+
+        assert!(!expired_before_transfer);
+        assert_eq!(now + DEFAULT_RETRY, first_retry_at);
+
+        assert_eq!(now + Duration::from_secs(3600), refresh_at);
+        assert!(!expired_after_retry);
+        assert_eq!(now + Duration::from_secs(3600 + 900), retry_at);
+        assert!(expired_after_expire);
+
+        assert!(!is_due_after_refresh);
+        assert!(is_due_after_request);
+        assert!(!is_due_after_start);
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_full_and_incremental_transfers() -> Result<()> {
+        /* Arrange */
+        let current_records = vec![soa(1), a_record("www.example.com", 1)];
+
+        let full_transfer = vec![soa(2), a_record("www.example.com", 2), soa(2)];
+        let incremental_transfer = vec![
+            soa(3),
+            soa(1),
+            a_record("www.example.com", 1),
+            soa(2),
+            a_record("www.example.com", 2),
+            soa(2),
+            soa(3),
+            a_record("mail.example.com", 3),
+            soa(3),
+        ];
+        let up_to_date_transfer = vec![soa(1)];
+        let empty_zone_transfer = vec![soa(2), soa(2)];
+
+        /* Act */
+        let fully_transferred_records = apply_transfer(&[], &full_transfer, QueryType::AXFR)?;
+        let incrementally_transferred_records =
+            apply_transfer(&current_records, &incremental_transfer, QueryType::IXFR)?;
+        let up_to_date_records =
+            apply_transfer(&current_records, &up_to_date_transfer, QueryType::IXFR)?;
+        let mismatched_records = apply_transfer(&[soa(2)], &incremental_transfer, QueryType::IXFR);
+
+        // Two SOAs are an empty zone, unless the second one is of the secondary's version
+        let empty_zone_records =
+            apply_transfer(&current_records, &empty_zone_transfer, QueryType::AXFR)?;
+        let empty_zone_ixfr_records =
+            apply_transfer(&current_records, &empty_zone_transfer, QueryType::IXFR)?;
+        let unchanged_ixfr_records =
+            apply_transfer(&[soa(2)], &empty_zone_transfer, QueryType::IXFR)?;
+
+        /* Assert */
+        assert_eq!(
+            vec![soa(2), a_record("www.example.com", 2)],
+            fully_transferred_records
+        );
+        assert_eq!(
+            vec![
+                soa(3),
+                a_record("www.example.com", 2),
+                a_record("mail.example.com", 3)
+            ],
+            incrementally_transferred_records
+        );
+        assert_eq!(current_records, up_to_date_records);
+        assert!(mismatched_records.is_err());
+
+        assert_eq!(vec![soa(2)], empty_zone_records);
+        assert_eq!(vec![soa(2)], empty_zone_ixfr_records);
+        assert_eq!(vec![soa(2)], unchanged_ixfr_records);
+
+        assert!(is_transfer_complete(&full_transfer, None));
+        assert!(is_transfer_complete(&incremental_transfer, Some(1)));
+        assert!(!is_transfer_complete(&incremental_transfer[..6], Some(1)));
+        assert!(is_transfer_complete(&up_to_date_transfer, Some(1)));
+        assert!(!is_transfer_complete(&[soa(2)], Some(1)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    // Jobs beyond the queue size are dropped rather than piling up while the workers are busy
+    pub fn new(size: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || Self::run_worker(receiver))
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn run_worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // The lock is released before the job runs, so other workers can pick up jobs
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return, // The pool has been dropped
+            };
+
+            job();
+        }
+    }
+
+    // Returns whether the job was queued
+    pub fn execute<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &self.sender {
+            Some(sender) => sender.try_send(Box::new(job)).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers finish their queued jobs and exit
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn execute_jobs() {
+        /* Arrange */
+        let pool = ThreadPool::new(4, 100);
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        /* Act */
+        let mut queued_count = 0;
+        for _ in 0..100 {
+            let counter = Arc::clone(&counter);
+            if pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }) {
+                queued_count += 1;
+            }
+        }
+        drop(pool);
+
+        /* Assert */
+        assert_eq!(100, queued_count);
+        assert_eq!(100, counter.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn drop_jobs_when_queue_is_full() {
+        /* Arrange */
+        let pool = ThreadPool::new(1, 1);
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+
+        /* Act */
+        // Keeps the only worker busy
+        let blocking_queued = pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = release_receiver.recv();
+        });
+        started_receiver.recv().unwrap();
+
+        let queued = pool.execute(|| {});
+        let overflowing_queued = pool.execute(|| {});
+
+        drop(release_sender);
+        drop(pool);
+
+        /* Assert */
+        assert!(blocking_queued);
+        assert!(queued);
+        assert!(!overflowing_queued);
+    }
+}
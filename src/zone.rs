@@ -0,0 +1,708 @@
+use crate::dns_packet::{is_subdomain, Packet, QueryType, ResourceRecord, ReturnCode};
+use crate::packet_buffer::split_labels;
+use crate::zone_file;
+
+use std::collections::{HashMap, HashSet};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const MAX_CNAME_CHAIN_LEN: usize = 8;
+const MAX_ZONE_HISTORY_LEN: usize = 16;
+
+// Compares serials using sequence space arithmetic, so that they can wrap around (RFC 1982)
+pub fn is_serial_newer(serial: u32, than: u32) -> bool {
+    serial != than && (serial.wrapping_sub(than) as i32) > 0
+}
+
+pub fn get_soa_serial(record: &ResourceRecord) -> Option<u32> {
+    match *record {
+        ResourceRecord::SOA { serial, .. } => Some(serial),
+        _ => None,
+    }
+}
+
+// The name without its first label, which may contain escaped dots
+fn parent_name(name: &str) -> Option<&str> {
+    let first_label = split_labels(name)[0];
+    name.get(first_label.len() + 1..)
+}
+
+// The changes between two versions of a zone, kept for incremental transfers (RFC 1995)
+#[derive(Clone, Debug)]
+struct ZoneDiff {
+    from_soa: ResourceRecord,
+    deleted_records: Vec<ResourceRecord>,
+    to_soa: ResourceRecord,
+    added_records: Vec<ResourceRecord>,
+}
+
+pub struct Zone {
+    origin: String,
+    records: HashMap<String, Vec<ResourceRecord>>,
+    history: Vec<ZoneDiff>,
+}
+
+impl Zone {
+    pub fn from_records(origin: &str, records: Vec<ResourceRecord>) -> Result<Self> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut zone_records: HashMap<String, Vec<ResourceRecord>> = HashMap::new();
+
+        for record in records {
+            let domain = record.get_domain().to_lowercase();
+            if !is_subdomain(&domain, &origin) {
+                return Err(format!("{} is outside of zone {}", domain, origin).into());
+            }
+
+            zone_records.entry(domain).or_default().push(record);
+        }
+
+        let soa_count = zone_records
+            .get(&origin)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| record.get_query_type() == QueryType::SOA)
+                    .count()
+            })
+            .unwrap_or(0);
+        if soa_count != 1 {
+            return Err(format!("Zone {} needs exactly one SOA record at its apex", origin).into());
+        }
+
+        // A name with a CNAME can't have any other data (RFC 1034 section 3.6.2)
+        for (domain, records) in zone_records.iter() {
+            let has_cname = records
+                .iter()
+                .any(|record| record.get_query_type() == QueryType::CNAME);
+            if has_cname && records.len() > 1 {
+                return Err(format!("{} has a CNAME alongside other records", domain).into());
+            }
+        }
+
+        Ok(Zone {
+            origin,
+            records: zone_records,
+            history: Vec::new(),
+        })
+    }
+
+    pub fn from_file(origin: &str, path: &str) -> Result<Self> {
+        Self::from_records(origin, zone_file::parse_file(path, origin)?)
+    }
+
+    pub fn get_origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn get_soa(&self) -> &ResourceRecord {
+        self.records[&self.origin]
+            .iter()
+            .find(|record| record.get_query_type() == QueryType::SOA)
+            .unwrap()
+    }
+
+    pub fn get_serial(&self) -> u32 {
+        get_soa_serial(self.get_soa()).unwrap()
+    }
+
+    // All of the zone's records apart from the SOA
+    fn get_data_records(&self) -> Vec<ResourceRecord> {
+        let mut domains: Vec<&String> = self.records.keys().collect();
+        domains.sort();
+
+        domains
+            .into_iter()
+            .flat_map(|domain| self.records[domain].iter())
+            .filter(|record| record.get_query_type() != QueryType::SOA)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_all_records(&self) -> Vec<ResourceRecord> {
+        let mut records = vec![self.get_soa().clone()];
+        records.extend(self.get_data_records());
+
+        records
+    }
+
+    // All of the zone's records, between two copies of its SOA as in zone transfers (RFC 5936)
+    pub fn get_transfer_records(&self) -> Vec<ResourceRecord> {
+        let mut records = self.get_all_records();
+        records.push(self.get_soa().clone());
+
+        records
+    }
+
+    // The changes since the given serial in the format of incremental transfers (RFC 1995), or
+    // None if they aren't known
+    pub fn get_incremental_transfer_records(
+        &self,
+        from_serial: u32,
+    ) -> Option<Vec<ResourceRecord>> {
+        let soa = self.get_soa().clone();
+
+        // Secondaries which are up to date only get the current SOA
+        if !is_serial_newer(self.get_serial(), from_serial) {
+            return Some(vec![soa]);
+        }
+
+        let start = self
+            .history
+            .iter()
+            .position(|diff| get_soa_serial(&diff.from_soa) == Some(from_serial))?;
+
+        let mut records = vec![soa.clone()];
+        for diff in &self.history[start..] {
+            records.push(diff.from_soa.clone());
+            records.extend(diff.deleted_records.iter().cloned());
+            records.push(diff.to_soa.clone());
+            records.extend(diff.added_records.iter().cloned());
+        }
+        records.push(soa);
+
+        Some(records)
+    }
+
+    // Keeps the history of the previous version of the zone, along with the changes made since
+    fn inherit_history(&mut self, previous: Zone) {
+        // Changes can only be tracked while the serial keeps increasing
+        if !is_serial_newer(self.get_serial(), previous.get_serial()) {
+            return;
+        }
+
+        let previous_records = previous.get_data_records();
+        let records = self.get_data_records();
+
+        let previous_record_set: HashSet<&ResourceRecord> = previous_records.iter().collect();
+        let record_set: HashSet<&ResourceRecord> = records.iter().collect();
+
+        let diff = ZoneDiff {
+            from_soa: previous.get_soa().clone(),
+            deleted_records: previous_records
+                .iter()
+                .filter(|record| !record_set.contains(record))
+                .cloned()
+                .collect(),
+            to_soa: self.get_soa().clone(),
+            added_records: records
+                .iter()
+                .filter(|record| !previous_record_set.contains(record))
+                .cloned()
+                .collect(),
+        };
+
+        self.history = previous.history;
+        self.history.push(diff);
+        if self.history.len() > MAX_ZONE_HISTORY_LEN {
+            self.history.remove(0);
+        }
+    }
+
+    fn get_records(&self, domain: &str, qtype: QueryType) -> Vec<ResourceRecord> {
+        self.records
+            .get(domain)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| record.get_query_type() == qtype)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Returns the topmost name between the apex and the given name which has been delegated
+    fn find_delegation(&self, name: &str) -> Option<String> {
+        let mut delegation = None;
+        let mut ancestor = name;
+
+        while ancestor.len() > self.origin.len() {
+            if !self.get_records(ancestor, QueryType::NS).is_empty() {
+                delegation = Some(ancestor.to_string());
+            }
+
+            ancestor = match parent_name(ancestor) {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+
+        delegation
+    }
+
+    // Names without records of their own still exist if there are names below them
+    fn has_descendants(&self, name: &str) -> bool {
+        self.records
+            .keys()
+            .any(|domain| domain != name && is_subdomain(domain, name))
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.records.contains_key(name) || self.has_descendants(name)
+    }
+
+    // Returns the records at the name, which are synthesised from a wildcard if the name doesn't
+    // exist (RFC 4592 section 3.3.1)
+    fn find_records(&self, name: &str) -> Option<Vec<ResourceRecord>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        if self.has_descendants(name) {
+            return Some(Vec::new());
+        }
+
+        // Only the wildcard directly below the closest existing ancestor can match
+        let mut closest_encloser = name;
+        while closest_encloser.len() > self.origin.len() && !self.exists(closest_encloser) {
+            closest_encloser = parent_name(closest_encloser).unwrap_or("");
+        }
+
+        let wildcard = match closest_encloser {
+            "" => "*".to_string(),
+            _ => format!("*.{}", closest_encloser),
+        };
+        let wildcard_records = self.records.get(&wildcard)?;
+
+        let synthesised_records = wildcard_records
+            .iter()
+            .map(|record| {
+                let mut record = record.clone();
+                record.set_domain(name);
+                record
+            })
+            .collect();
+
+        Some(synthesised_records)
+    }
+
+    // Adds the addresses of hosts in the zone, so that the client doesn't have to look them up
+    fn add_additional_records(&self, packet: &mut Packet, records: &[ResourceRecord]) {
+        for record in records {
+            let host = match record {
+                ResourceRecord::NS { host, .. } => host,
+                ResourceRecord::MX { exchange, .. } => exchange,
+                ResourceRecord::SRV { target, .. } => target,
+                _ => continue,
+            };
+
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                for addr_record in self.get_records(host, qtype) {
+                    if !packet.additional_records.contains(&addr_record) {
+                        packet.additional_records.push(addr_record);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_negative_soa(&self, packet: &mut Packet) {
+        // The TTL of negative answers is capped by the SOA's minimum field (RFC 2308)
+        let mut soa = self.get_soa().clone();
+        if let ResourceRecord::SOA { minimum, ttl, .. } = soa {
+            soa.set_ttl(minimum.min(ttl));
+        }
+
+        packet.authoritative_records.push(soa);
+    }
+
+    // Answers the query from the zone, following the algorithm in RFC 1034 section 4.3.2
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.response = true;
+
+        let mut name = qname.to_lowercase();
+
+        for _ in 0..MAX_CNAME_CHAIN_LEN {
+            if let Some(delegation) = self.find_delegation(&name) {
+                // Names in delegated zones are only answered with a referral to their nameservers
+                if packet.answer_records.is_empty() {
+                    let ns_records = self.get_records(&delegation, QueryType::NS);
+                    self.add_additional_records(&mut packet, &ns_records);
+                    packet.authoritative_records = ns_records;
+                }
+
+                return packet;
+            }
+
+            packet.header.authoritative_answer = true;
+
+            let records = match self.find_records(&name) {
+                Some(records) => records,
+                None => {
+                    packet.header.return_code = ReturnCode::NXDOMAIN;
+                    self.add_negative_soa(&mut packet);
+
+                    return packet;
+                }
+            };
+
+            let answer_records: Vec<ResourceRecord> = records
+                .iter()
+                .filter(|record| record.get_query_type() == qtype)
+                .cloned()
+                .collect();
+            if !answer_records.is_empty() {
+                self.add_additional_records(&mut packet, &answer_records);
+                packet.answer_records.extend(answer_records);
+
+                return packet;
+            }
+
+            let cname = records
+                .iter()
+                .find(|record| record.get_query_type() == QueryType::CNAME);
+            let cname = match cname {
+                Some(cname) if qtype != QueryType::CNAME => cname.clone(),
+                _ => {
+                    self.add_negative_soa(&mut packet);
+                    return packet;
+                }
+            };
+
+            name = match &cname {
+                ResourceRecord::CNAME { host, .. } => host.to_lowercase(),
+                _ => unreachable!(),
+            };
+            packet.answer_records.push(cname);
+
+            // A loop can't be answered any more than it could be resolved
+            if packet
+                .answer_records
+                .iter()
+                .any(|record| record.get_domain().eq_ignore_ascii_case(&name))
+            {
+                eprintln!("CNAME loop in zone {} at {}", self.origin, name);
+                return Self::server_failure(packet);
+            }
+
+            // Targets outside of the zone are left to the client to look up
+            if !is_subdomain(&name, &self.origin) {
+                return packet;
+            }
+        }
+
+        Self::server_failure(packet)
+    }
+
+    fn server_failure(mut packet: Packet) -> Packet {
+        packet.header.authoritative_answer = false;
+        packet.header.return_code = ReturnCode::SERVFAIL;
+        packet.answer_records.clear();
+        packet.additional_records.clear();
+
+        packet
+    }
+}
+
+// The zones which are served, picked by the longest origin which the name is part of
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn new() -> Self {
+        Zones { zones: Vec::new() }
+    }
+
+    // Returns the serial of the version which the zone replaces
+    pub fn insert(&mut self, mut zone: Zone) -> Option<u32> {
+        let previous = self.remove(&zone.origin);
+        let previous_serial = previous.as_ref().map(Zone::get_serial);
+        if let Some(previous) = previous {
+            zone.inherit_history(previous);
+        }
+
+        self.zones.push(zone);
+        previous_serial
+    }
+
+    pub fn remove(&mut self, origin: &str) -> Option<Zone> {
+        let i = self.zones.iter().position(|zone| zone.origin == origin)?;
+
+        Some(self.zones.remove(i))
+    }
+
+    pub fn find(&self, qname: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| is_subdomain(qname, &zone.origin))
+            .max_by_key(|zone| zone.origin.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn example_zone() -> Result<Zone> {
+        let zone_file = "
+$TTL 3600
+@               SOA     ns1 hostmaster 1 3600 900 604800 300
+@               NS      ns1
+@               MX      10 mail
+ns1             A       192.0.2.1
+mail            A       192.0.2.2
+www             CNAME   web.internal
+web.internal    A       192.0.2.3
+external        CNAME   www.google.com.
+sub             NS      ns1.sub
+ns1.sub         A       192.0.2.4
+";
+
+        Zone::from_records("example.com", zone_file::parse(zone_file, "example.com")?)
+    }
+
+    fn domains_and_types(records: &[ResourceRecord]) -> Vec<(&str, QueryType)> {
+        records
+            .iter()
+            .map(|record| (record.get_domain(), record.get_query_type()))
+            .collect()
+    }
+
+    #[test]
+    fn answer_from_zone() -> Result<()> {
+        /* Arrange */
+        let zone = example_zone()?;
+
+        /* Act */
+        let mx_answer = zone.lookup("Example.com", QueryType::MX);
+        let cname_answer = zone.lookup("www.example.com", QueryType::A);
+        let external_cname_answer = zone.lookup("external.example.com", QueryType::A);
+
+        /* Assert */
+        assert!(mx_answer.header.authoritative_answer);
+        assert_eq!(ReturnCode::NOERROR, mx_answer.header.return_code);
+        assert_eq!(
+            vec![("example.com", QueryType::MX)],
+            domains_and_types(&mx_answer.answer_records)
+        );
+        assert_eq!(
+            vec![ResourceRecord::A {
+                domain: "mail.example.com".to_string(),
+                ip_addr: Ipv4Addr::new(192, 0, 2, 2),
+                ttl: 3600,
+            }],
+            mx_answer.additional_records
+        );
+
+        assert_eq!(
+            vec![
+                ("www.example.com", QueryType::CNAME),
+                ("web.internal.example.com", QueryType::A)
+            ],
+            domains_and_types(&cname_answer.answer_records)
+        );
+
+        assert_eq!(
+            vec![("external.example.com", QueryType::CNAME)],
+            domains_and_types(&external_cname_answer.answer_records)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_negatively_and_with_referrals() -> Result<()> {
+        /* Arrange */
+        let zone = example_zone()?;
+
+        /* Act */
+        let nxdomain = zone.lookup("nope.example.com", QueryType::A);
+        let nodata = zone.lookup("mail.example.com", QueryType::AAAA);
+        let empty_non_terminal = zone.lookup("internal.example.com", QueryType::A);
+        let referral = zone.lookup("www.sub.example.com", QueryType::A);
+        // The escaped dot leaves the name outside of the delegated sub.example.com
+        let escaped_dot_nxdomain = zone.lookup("www\\.sub.example.com", QueryType::A);
+
+        /* Assert */
+        assert!(nxdomain.header.authoritative_answer);
+        assert_eq!(ReturnCode::NXDOMAIN, nxdomain.header.return_code);
+        assert_eq!(
+            vec![("example.com", QueryType::SOA)],
+            domains_and_types(&nxdomain.authoritative_records)
+        );
+        // Negative answers last for as long as the SOA's minimum field
+        assert_eq!(300, nxdomain.authoritative_records[0].get_ttl());
+
+        assert_eq!(ReturnCode::NOERROR, nodata.header.return_code);
+        assert!(nodata.answer_records.is_empty());
+        assert_eq!(1, nodata.authoritative_records.len());
+
+        assert_eq!(ReturnCode::NOERROR, empty_non_terminal.header.return_code);
+        assert!(empty_non_terminal.answer_records.is_empty());
+        assert_eq!(1, empty_non_terminal.authoritative_records.len());
+
+        assert!(!referral.header.authoritative_answer);
+        assert!(referral.answer_records.is_empty());
+        assert_eq!(
+            vec![("sub.example.com", QueryType::NS)],
+            domains_and_types(&referral.authoritative_records)
+        );
+        assert_eq!(
+            vec![("ns1.sub.example.com", QueryType::A)],
+            domains_and_types(&referral.additional_records)
+        );
+
+        assert!(escaped_dot_nxdomain.header.authoritative_answer);
+        assert_eq!(
+            ReturnCode::NXDOMAIN,
+            escaped_dot_nxdomain.header.return_code
+        );
+
+        assert!(Zone::from_records("example.com", Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_from_wildcards() -> Result<()> {
+        /* Arrange */
+        let zone_file = "
+$TTL 3600
+@                   SOA     ns1 hostmaster 1 3600 900 604800 300
+@                   NS      ns1
+ns1                 A       192.0.2.1
+*.preview           A       192.0.2.10
+*.preview           TXT     \"preview\"
+exact.preview       A       192.0.2.11
+host.sub.preview    A       192.0.2.12
+*.alias             CNAME   ns1
+";
+        let zone = Zone::from_records("example.com", zone_file::parse(zone_file, "example.com")?)?;
+
+        /* Act */
+        let wildcard_answer = zone.lookup("branch-1.preview.example.com", QueryType::A);
+        let deep_wildcard_answer = zone.lookup("a.b.preview.example.com", QueryType::TXT);
+        let wildcard_nodata = zone.lookup("branch-1.preview.example.com", QueryType::MX);
+        let exact_nodata = zone.lookup("exact.preview.example.com", QueryType::TXT);
+        let empty_non_terminal = zone.lookup("sub.preview.example.com", QueryType::A);
+        let below_empty_non_terminal = zone.lookup("other.sub.preview.example.com", QueryType::A);
+        let wildcard_cname = zone.lookup("branch-2.alias.example.com", QueryType::A);
+
+        /* Assert */
+        assert!(wildcard_answer.header.authoritative_answer);
+        assert_eq!(
+            vec![ResourceRecord::A {
+                domain: "branch-1.preview.example.com".to_string(),
+                ip_addr: Ipv4Addr::new(192, 0, 2, 10),
+                ttl: 3600,
+            }],
+            wildcard_answer.answer_records
+        );
+
+        assert_eq!(
+            vec![("a.b.preview.example.com", QueryType::TXT)],
+            domains_and_types(&deep_wildcard_answer.answer_records)
+        );
+
+        assert_eq!(ReturnCode::NOERROR, wildcard_nodata.header.return_code);
+        assert!(wildcard_nodata.answer_records.is_empty());
+
+        // Names which exist aren't matched by wildcards, even without records of their own
+        assert!(exact_nodata.answer_records.is_empty());
+        assert_eq!(ReturnCode::NOERROR, empty_non_terminal.header.return_code);
+        assert!(empty_non_terminal.answer_records.is_empty());
+        assert_eq!(
+            ReturnCode::NXDOMAIN,
+            below_empty_non_terminal.header.return_code
+        );
+
+        assert_eq!(
+            vec![
+                ("branch-2.alias.example.com", QueryType::CNAME),
+                ("ns1.example.com", QueryType::A)
+            ],
+            domains_and_types(&wildcard_cname.answer_records)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_history_for_incremental_transfers() -> Result<()> {
+        /* Arrange */
+        let zone_versions = [
+            "@ 3600 SOA ns1 hostmaster 1 3600 900 604800 300\nwww 3600 A 192.0.2.1\n",
+            "@ 3600 SOA ns1 hostmaster 2 3600 900 604800 300\nwww 3600 A 192.0.2.2\n",
+            "@ 3600 SOA ns1 hostmaster 3 3600 900 604800 300\nwww 3600 A 192.0.2.2\nmail 3600 A 192.0.2.3\n",
+        ];
+
+        let mut zones = Zones::new();
+
+        /* Act */
+        for zone_file in zone_versions.iter() {
+            zones.insert(Zone::from_records(
+                "example.com",
+                zone_file::parse(zone_file, "example.com")?,
+            )?);
+        }
+        let zone = zones.find("example.com").unwrap();
+
+        let changes = zone.get_incremental_transfer_records(1).unwrap();
+        let no_changes = zone.get_incremental_transfer_records(3).unwrap();
+        let unknown_changes = zone.get_incremental_transfer_records(0);
+
+        /* Assert */
+        let serials_and_types: Vec<(Option<u32>, QueryType)> = changes
+            .iter()
+            .map(|record| (get_soa_serial(record), record.get_query_type()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(3), QueryType::SOA),
+                (Some(1), QueryType::SOA),
+                (None, QueryType::A),
+                (Some(2), QueryType::SOA),
+                (None, QueryType::A),
+                (Some(2), QueryType::SOA),
+                (Some(3), QueryType::SOA),
+                (None, QueryType::A),
+                (Some(3), QueryType::SOA),
+            ],
+            serials_and_types
+        );
+
+        assert_eq!(1, no_changes.len());
+        assert!(unknown_changes.is_none());
+
+        assert!(is_serial_newer(1, u32::MAX));
+        assert!(!is_serial_newer(u32::MAX, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_on_cname_loop() -> Result<()> {
+        /* Arrange */
+        let zone_file = "
+$TTL 3600
+@               SOA     ns1 hostmaster 1 3600 900 604800 300
+loop1           CNAME   loop2
+loop2           CNAME   loop1
+long            CNAME   long1
+long1           CNAME   long2
+long2           CNAME   long3
+long3           CNAME   long4
+long4           CNAME   long5
+long5           CNAME   long6
+long6           CNAME   long7
+long7           CNAME   long8
+long8           A       192.0.2.1
+";
+        let zone = Zone::from_records("example.com", zone_file::parse(zone_file, "example.com")?)?;
+
+        /* Act */
+        let loop_answer = zone.lookup("loop1.example.com", QueryType::A);
+        let long_answer = zone.lookup("long.example.com", QueryType::A);
+
+        /* Assert */
+        for answer in [loop_answer, long_answer] {
+            assert_eq!(ReturnCode::SERVFAIL, answer.header.return_code);
+            assert!(answer.answer_records.is_empty());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,635 @@
+use crate::dns_packet::ResourceRecord;
+use crate::packet_buffer::{unescape, CHARACTER_STRING_MAX_LEN};
+
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Debug, PartialEq)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+// An entry, which may span several physical lines when it uses parentheses
+#[derive(Debug, PartialEq)]
+struct Entry {
+    line_number: usize,
+    starts_with_blank: bool,
+    tokens: Vec<Token>,
+}
+
+// Splits the file into entries, leaving out comments and joining parenthesised lines
+fn tokenize(text: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    let mut line_number = 1;
+    let mut entry = Entry {
+        line_number,
+        starts_with_blank: matches!(chars.peek(), Some(' ') | Some('\t')),
+        tokens: Vec::new(),
+    };
+    let mut paren_depth = 0;
+    let mut token = String::new();
+
+    while let Some(c) = chars.next() {
+        let is_separator = matches!(c, ' ' | '\t' | '\r' | '\n' | ';' | '(' | ')' | '"');
+        if is_separator && !token.is_empty() {
+            entry.tokens.push(Token {
+                text: std::mem::take(&mut token),
+                quoted: false,
+            });
+        }
+
+        match c {
+            ';' => {
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' => paren_depth += 1,
+            ')' => {
+                if paren_depth == 0 {
+                    return Err(format!("Unbalanced parentheses on line {}", line_number).into());
+                }
+                paren_depth -= 1;
+            }
+            '"' => {
+                let mut quoted_text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => push_escape(&mut quoted_text, &mut chars)?,
+                        Some('\n') | None => {
+                            return Err(
+                                format!("Unterminated string on line {}", line_number).into()
+                            );
+                        }
+                        Some(c) => quoted_text.push(c),
+                    }
+                }
+                entry.tokens.push(Token {
+                    text: quoted_text,
+                    quoted: true,
+                });
+            }
+            '\n' => {
+                line_number += 1;
+                if paren_depth > 0 {
+                    continue;
+                }
+
+                let next_entry = Entry {
+                    line_number,
+                    starts_with_blank: matches!(chars.peek(), Some(' ') | Some('\t')),
+                    tokens: Vec::new(),
+                };
+                let finished_entry = std::mem::replace(&mut entry, next_entry);
+                if !finished_entry.tokens.is_empty() {
+                    entries.push(finished_entry);
+                }
+            }
+            '\\' => push_escape(&mut token, &mut chars)?,
+            _ if is_separator => {}
+            _ => token.push(c),
+        }
+    }
+
+    if paren_depth > 0 {
+        return Err("Unbalanced parentheses at the end of the zone file".into());
+    }
+
+    if !token.is_empty() {
+        entry.tokens.push(Token {
+            text: token,
+            quoted: false,
+        });
+    }
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+enum Escape {
+    Char(char),
+    Byte(u8),
+}
+
+// Reads the character after a backslash, which is either literal or a "\DDD" decimal value
+fn read_escape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Escape> {
+    let first = chars.next().ok_or("Escape at the end of the zone file")?;
+    if !first.is_ascii_digit() {
+        return Ok(Escape::Char(first));
+    }
+
+    let mut digits = first.to_string();
+    for _ in 0..2 {
+        match chars.next() {
+            Some(c) if c.is_ascii_digit() => digits.push(c),
+            _ => return Err(format!("Invalid escape: \\{}", digits).into()),
+        }
+    }
+
+    let value = digits
+        .parse::<u8>()
+        .map_err(|_| format!("Invalid escape: \\{}", digits))?;
+
+    Ok(Escape::Byte(value))
+}
+
+// Dots and backslashes stay escaped, so that an escaped dot doesn't end the label it's in. Bytes
+// beyond ASCII stay escaped as well, as text can only hold them as part of UTF-8 characters.
+fn push_escape(text: &mut String, chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<()> {
+    let c = match read_escape(chars)? {
+        Escape::Byte(b) if !b.is_ascii() => {
+            text.push_str(&format!("\\{:03}", b));
+            return Ok(());
+        }
+        Escape::Byte(b) => b as char,
+        Escape::Char(c) => c,
+    };
+
+    if c == '.' || c == '\\' {
+        text.push('\\');
+    }
+    text.push(c);
+
+    Ok(())
+}
+
+// Parses TTLs given both in seconds and with units, e.g. "1h30m"
+fn parse_ttl(ttl: &str) -> Result<u32> {
+    if let Ok(seconds) = ttl.parse::<u32>() {
+        return Ok(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut value = String::new();
+
+    for c in ttl.chars() {
+        if c.is_ascii_digit() {
+            value.push(c);
+            continue;
+        }
+
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(format!("Invalid TTL: {}", ttl).into()),
+        };
+        if value.is_empty() {
+            return Err(format!("Invalid TTL: {}", ttl).into());
+        }
+
+        total = value
+            .parse::<u32>()?
+            .checked_mul(multiplier)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(|| format!("TTL is too large: {}", ttl))?;
+        value.clear();
+    }
+
+    if !value.is_empty() {
+        return Err(format!("Invalid TTL: {}", ttl).into());
+    }
+
+    Ok(total)
+}
+
+fn is_ttl(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// Relative names are completed with the origin, and "@" stands for the origin itself
+fn parse_name(name: &str, origin: &str) -> Result<String> {
+    // Names are kept as text, which only holds bytes beyond ASCII as UTF-8
+    if !unescape(name).is_ascii() {
+        return Err(format!("Name contains bytes outside of ASCII: {}", name).into());
+    }
+
+    let name = if name == "@" {
+        origin.to_string()
+    } else if let Some(absolute_name) = name.strip_suffix('.').filter(|rest| !rest.ends_with('\\'))
+    {
+        absolute_name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    };
+
+    Ok(name.to_lowercase())
+}
+
+struct Parser {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    base_dir: PathBuf,
+    include_depth: usize,
+    records: Vec<ResourceRecord>,
+}
+
+impl Parser {
+    fn parse(&mut self, text: &str) -> Result<()> {
+        for entry in tokenize(text)? {
+            let result = if !entry.tokens[0].quoted && entry.tokens[0].text.starts_with('$') {
+                self.parse_directive(&entry.tokens)
+            } else {
+                self.parse_record(&entry)
+            };
+
+            result.map_err(|e| format!("Line {}: {}", entry.line_number, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_directive(&mut self, tokens: &[Token]) -> Result<()> {
+        let directive = tokens[0].text.to_uppercase();
+        let arg = &tokens.get(1).ok_or("Missing directive argument")?.text;
+
+        match directive.as_str() {
+            "$ORIGIN" => self.origin = parse_name(arg, &self.origin)?,
+            "$TTL" => self.default_ttl = Some(parse_ttl(arg)?),
+            "$INCLUDE" => {
+                if self.include_depth >= MAX_INCLUDE_DEPTH {
+                    return Err("Too many nested $INCLUDE directives".into());
+                }
+
+                let path = self.base_dir.join(arg);
+                let origin = match tokens.get(2) {
+                    Some(origin) => parse_name(&origin.text, &self.origin)?,
+                    None => self.origin.clone(),
+                };
+
+                // The included file can't change the origin or the TTL of the including one
+                let mut parser = Parser {
+                    origin,
+                    default_ttl: self.default_ttl,
+                    last_ttl: self.last_ttl,
+                    last_owner: self.last_owner.clone(),
+                    base_dir: parent_dir(&path),
+                    include_depth: self.include_depth + 1,
+                    records: Vec::new(),
+                };
+                parser.parse(&fs::read_to_string(&path)?)?;
+
+                self.records.append(&mut parser.records);
+            }
+            _ => return Err(format!("Unsupported directive: {}", directive).into()),
+        }
+
+        Ok(())
+    }
+
+    fn parse_record(&mut self, entry: &Entry) -> Result<()> {
+        let mut tokens = entry.tokens.iter().map(|token| token.text.as_str());
+
+        let domain = if entry.starts_with_blank {
+            self.last_owner
+                .clone()
+                .ok_or("Record doesn't have an owner name")?
+        } else {
+            parse_name(tokens.next().unwrap(), &self.origin)?
+        };
+
+        // The TTL and the class are both optional, and can come in either order
+        let mut ttl = None;
+        let rtype = loop {
+            let token = tokens.next().ok_or("Missing record type")?;
+
+            if is_ttl(token) && ttl.is_none() {
+                ttl = Some(parse_ttl(token)?);
+            } else if token.eq_ignore_ascii_case("IN") {
+                continue;
+            } else if ["CS", "CH", "HS"].contains(&token.to_uppercase().as_str()) {
+                return Err(format!("Unsupported class: {}", token).into());
+            } else {
+                break token.to_uppercase();
+            }
+        };
+
+        let ttl = match ttl.or(self.default_ttl).or(self.last_ttl) {
+            Some(ttl) => ttl,
+            None => return Err("Record doesn't have a TTL and there's no $TTL".into()),
+        };
+
+        let rdata: Vec<&str> = tokens.collect();
+        let record = self.parse_rdata(domain.clone(), &rtype, &rdata, ttl)?;
+
+        self.records.push(record);
+        self.last_owner = Some(domain);
+        self.last_ttl = Some(ttl);
+
+        Ok(())
+    }
+
+    fn parse_rdata(
+        &self,
+        domain: String,
+        rtype: &str,
+        rdata: &[&str],
+        ttl: u32,
+    ) -> Result<ResourceRecord> {
+        let expected_len = match rtype {
+            "A" | "AAAA" | "NS" | "CNAME" | "PTR" => 1,
+            "MX" => 2,
+            "SRV" => 4,
+            "SOA" => 7,
+            "TXT" => rdata.len().max(1),
+            _ => return Err(format!("Unsupported record type: {}", rtype).into()),
+        };
+        if rdata.len() != expected_len {
+            return Err(format!("Expected {} values for a {} record", expected_len, rtype).into());
+        }
+
+        let name = |i: usize| parse_name(rdata[i], &self.origin);
+
+        let record = match rtype {
+            "A" => ResourceRecord::A {
+                domain,
+                ip_addr: rdata[0].parse::<Ipv4Addr>()?,
+                ttl,
+            },
+            "AAAA" => ResourceRecord::AAAA {
+                domain,
+                ip_addr: rdata[0].parse::<Ipv6Addr>()?,
+                ttl,
+            },
+            "NS" => ResourceRecord::NS {
+                domain,
+                host: name(0)?,
+                ttl,
+            },
+            "CNAME" => ResourceRecord::CNAME {
+                domain,
+                host: name(0)?,
+                ttl,
+            },
+            "PTR" => ResourceRecord::PTR {
+                domain,
+                host: name(0)?,
+                ttl,
+            },
+            "MX" => ResourceRecord::MX {
+                domain,
+                priority: rdata[0].parse::<u16>()?,
+                exchange: name(1)?,
+                ttl,
+            },
+            "TXT" => {
+                let data: Vec<Vec<u8>> = rdata.iter().map(|string| unescape(string)).collect();
+                if data
+                    .iter()
+                    .any(|string| string.len() > CHARACTER_STRING_MAX_LEN)
+                {
+                    return Err("TXT strings can't be longer than 255 bytes".into());
+                }
+
+                ResourceRecord::TXT { domain, data, ttl }
+            }
+            "SRV" => ResourceRecord::SRV {
+                domain,
+                priority: rdata[0].parse::<u16>()?,
+                weight: rdata[1].parse::<u16>()?,
+                port: rdata[2].parse::<u16>()?,
+                target: name(3)?,
+                ttl,
+            },
+            "SOA" => ResourceRecord::SOA {
+                domain,
+                mname: name(0)?,
+                rname: name(1)?,
+                serial: rdata[2].parse::<u32>()?,
+                refresh: parse_ttl(rdata[3])?,
+                retry: parse_ttl(rdata[4])?,
+                expire: parse_ttl(rdata[5])?,
+                minimum: parse_ttl(rdata[6])?,
+                ttl,
+            },
+            _ => unreachable!(),
+        };
+
+        Ok(record)
+    }
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+// Parses records in the master file format (RFC 1035 section 5), with $INCLUDE paths being
+// relative to the current directory
+pub fn parse(text: &str, origin: &str) -> Result<Vec<ResourceRecord>> {
+    parse_with_base_dir(text, origin, PathBuf::new())
+}
+
+// $INCLUDE paths are relative to the directory of the zone file
+pub fn parse_file(path: &str, origin: &str) -> Result<Vec<ResourceRecord>> {
+    let text = fs::read_to_string(path)?;
+    parse_with_base_dir(&text, origin, parent_dir(Path::new(path)))
+        .map_err(|e| format!("{}: {}", path, e).into())
+}
+
+fn parse_with_base_dir(text: &str, origin: &str, base_dir: PathBuf) -> Result<Vec<ResourceRecord>> {
+    let mut parser = Parser {
+        origin: parse_name(origin, "")?,
+        default_ttl: None,
+        last_ttl: None,
+        last_owner: None,
+        base_dir,
+        include_depth: 0,
+        records: Vec::new(),
+    };
+    parser.parse(text)?;
+
+    Ok(parser.records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::dns_packet::is_subdomain;
+
+    #[test]
+    fn parse_master_file() -> Result<()> {
+        /* Arrange */
+        let zone_file = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2021070100 ; Serial
+            1h         ; Refresh
+            15m        ; Retry
+            1w         ; Expire
+            300 )      ; Minimum
+    IN  NS  ns1
+    IN  MX  10 mail.example.com.
+ns1 300 IN A 192.0.2.1
+        IN AAAA 2001:db8::1
+www     CNAME   @
+txt     TXT     "v=spf1 -all" "with \"quotes\"; and a semicolon"
+$ORIGIN sub.example.com.
+_sip._tcp IN 60 SRV 10 20 5060 sip
+"#;
+
+        /* Act */
+        let records = parse(zone_file, "example.com")?;
+
+        /* Assert */
+        assert_eq!(
+            vec![
+                ResourceRecord::SOA {
+                    domain: "example.com".to_string(),
+                    mname: "ns1.example.com".to_string(),
+                    rname: "hostmaster.example.com".to_string(),
+                    serial: 2021070100,
+                    refresh: 3600,
+                    retry: 900,
+                    expire: 604800,
+                    minimum: 300,
+                    ttl: 3600,
+                },
+                ResourceRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.com".to_string(),
+                    ttl: 3600,
+                },
+                ResourceRecord::MX {
+                    domain: "example.com".to_string(),
+                    priority: 10,
+                    exchange: "mail.example.com".to_string(),
+                    ttl: 3600,
+                },
+                ResourceRecord::A {
+                    domain: "ns1.example.com".to_string(),
+                    ip_addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: 300,
+                },
+                ResourceRecord::AAAA {
+                    domain: "ns1.example.com".to_string(),
+                    ip_addr: "2001:db8::1".parse::<Ipv6Addr>()?,
+                    ttl: 3600,
+                },
+                ResourceRecord::CNAME {
+                    domain: "www.example.com".to_string(),
+                    host: "example.com".to_string(),
+                    ttl: 3600,
+                },
+                ResourceRecord::TXT {
+                    domain: "txt.example.com".to_string(),
+                    data: vec![
+                        b"v=spf1 -all".to_vec(),
+                        b"with \"quotes\"; and a semicolon".to_vec()
+                    ],
+                    ttl: 3600,
+                },
+                ResourceRecord::SRV {
+                    domain: "_sip._tcp.sub.example.com".to_string(),
+                    priority: 10,
+                    weight: 20,
+                    port: 5060,
+                    target: "sip.sub.example.com".to_string(),
+                    ttl: 60,
+                },
+            ],
+            records
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_included_file() -> Result<()> {
+        /* Arrange */
+        let dir = std::env::temp_dir().join(format!("iris-zone-file-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+
+        fs::write(
+            dir.join("example.com.zone"),
+            "$TTL 300\n$INCLUDE hosts.zone internal\nmail A 192.0.2.2\n",
+        )?;
+        fs::write(dir.join("hosts.zone"), "www A 192.0.2.1\n")?;
+
+        /* Act */
+        let path = dir.join("example.com.zone");
+        let records = parse_file(path.to_str().unwrap(), "example.com.");
+
+        let missing_ttl = parse("www A 192.0.2.1", "example.com");
+        let unbalanced = parse("@ 300 SOA ns1 hostmaster ( 1 2 3 4 5", "example.com");
+
+        fs::remove_dir_all(&dir)?;
+
+        /* Assert */
+        let domains: Vec<String> = records?
+            .iter()
+            .map(|record| record.get_domain().to_string())
+            .collect();
+        assert_eq!(
+            vec!["www.internal.example.com", "mail.example.com"],
+            domains
+        );
+
+        assert!(missing_ttl.is_err());
+        assert!(unbalanced.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_escapes() -> Result<()> {
+        /* Arrange */
+        let zone_file = r#"
+$TTL 300
+first\.last     A       192.0.2.1
+txt             TXT     "a\.b\\c" d\046e
+"#;
+
+        /* Act */
+        let records = parse(zone_file, "example.com")?;
+        let non_ascii_txt = parse(r#"txt 300 TXT "\200" \255"#, "example.com")?;
+        let non_ascii_name = parse(r#"\200 300 A 192.0.2.1"#, "example.com");
+
+        /* Assert */
+        // The escaped dot is part of the first label
+        assert_eq!(r"first\.last.example.com", records[0].get_domain());
+        assert!(!is_subdomain(records[0].get_domain(), "last.example.com"));
+
+        assert_eq!(
+            ResourceRecord::TXT {
+                domain: "txt.example.com".to_string(),
+                data: vec![br"a.b\c".to_vec(), b"d.e".to_vec()],
+                ttl: 300,
+            },
+            records[1]
+        );
+
+        assert_eq!(
+            ResourceRecord::TXT {
+                domain: "txt.example.com".to_string(),
+                data: vec![vec![200], vec![255]],
+                ttl: 300,
+            },
+            non_ascii_txt[0]
+        );
+        assert!(non_ascii_name.is_err());
+
+        Ok(())
+    }
+}